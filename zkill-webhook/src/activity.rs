@@ -0,0 +1,296 @@
+use std::{collections::HashSet, time::Duration};
+
+use anyhow::{Error, Result};
+use serde::{Deserialize, Serialize};
+use sled::{Batch, IVec, Tree};
+use sled_ext::{key::Key, value::Value};
+use tokio::{select, time::interval};
+use tracing::log::{info, warn};
+
+use crate::{
+	model::zkb::Killmail,
+	stats::Entity,
+	storage::{decode, encode},
+	util::{now, parse_timestamp, stopped},
+	State,
+};
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+
+/// Killmails an entity needs to be involved in before its timezone is estimated.
+const MIN_INVOLVEMENTS: u64 = 10;
+
+/// Hours in the busiest stretch of the day, whose middle is taken as the entity's prime time.
+const PEAK_HOURS: usize = 3;
+
+/// Local hour prime time is assumed to be centred on.
+const PRIME_TIME: i64 = 20;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ActivityKey(pub Entity);
+
+/// Killmails an entity was involved in, by UTC hour of the day and day of the week.
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy)]
+pub struct Activity {
+	pub hours: [u64; 24],
+	/// Monday first.
+	pub weekdays: [u64; 7],
+	/// When a killmail involving the entity was last recorded.
+	pub last_seen: u64,
+}
+
+/// An entity's activity with an estimate of the timezone it plays in.
+#[derive(Debug, Serialize)]
+pub struct Profile {
+	/// Killmails the entity was involved in, on either side.
+	pub involvements: u64,
+	#[serde(flatten)]
+	pub activity: Activity,
+	/// Absent until there are enough killmails to go by.
+	pub timezone: Option<Timezone>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Timezone {
+	/// UTC hour in the middle of the busiest stretch of the day.
+	pub peak_hour: u64,
+	/// Offset from UTC, in hours, that puts the peak in the evening.
+	pub utc_offset: i64,
+	/// The EVE community's name for the timezone, e.g. `EUTZ`.
+	pub label: &'static str,
+}
+
+impl Key for ActivityKey {
+	type Value = Activity;
+
+	type Error = Error;
+
+	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
+		decode(bytes)
+	}
+
+	fn to_bytes(&self) -> Result<IVec, Self::Error> {
+		encode(self)
+	}
+}
+
+impl Value for Activity {
+	type Error = Error;
+
+	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
+		decode(bytes)
+	}
+
+	fn to_bytes(&self) -> Result<IVec, Self::Error> {
+		encode(self)
+	}
+}
+
+impl Activity {
+	fn involvements(&self) -> u64 {
+		self.hours.iter().sum()
+	}
+
+	fn timezone(&self) -> Option<Timezone> {
+		if self.involvements() < MIN_INVOLVEMENTS {
+			return None;
+		}
+
+		// the stretch can wrap around midnight
+		let busiest = (0..24)
+			.max_by_key(|start| {
+				let total = (0..PEAK_HOURS)
+					.map(|i| self.hours[(start + i) % 24])
+					.sum::<u64>();
+				// prefer the earliest of equally busy stretches
+				(total, std::cmp::Reverse(*start))
+			})
+			.unwrap_or_default();
+		let peak_hour = ((busiest + PEAK_HOURS / 2) % 24) as u64;
+
+		let label = match peak_hour {
+			6..=13 => "AUTZ",
+			14..=21 => "EUTZ",
+			_ => "USTZ",
+		};
+
+		Some(Timezone {
+			peak_hour,
+			utc_offset: (PRIME_TIME - peak_hour as i64 + 11).rem_euclid(24) - 11,
+			label,
+		})
+	}
+}
+
+/// The UTC hour of the day and day of the week, Monday first, of a Unix timestamp.
+fn slot(time: u64) -> (usize, usize) {
+	let hour = (time % DAY / HOUR) as usize;
+	// the epoch was a Thursday
+	let weekday = ((time / DAY + 3) % 7) as usize;
+	(hour, weekday)
+}
+
+/// Count the killmail's time once for each character, corporation and alliance involved.
+pub fn record(tree: &Tree, km: &Killmail) -> Result<()> {
+	let time = parse_timestamp(&km.killmail_time).unwrap_or_else(now);
+	let (hour, weekday) = slot(time);
+
+	let victim = &km.victim;
+	let mut entities = HashSet::new();
+	entities.insert(Entity::Corporation(victim.corporation_id));
+	entities.extend(victim.character_id.map(Entity::Character));
+	entities.extend(victim.alliance_id.map(Entity::Alliance));
+	for attacker in &km.attackers {
+		entities.extend(attacker.character_id.map(Entity::Character));
+		entities.extend(attacker.corporation_id.map(Entity::Corporation));
+		entities.extend(attacker.alliance_id.map(Entity::Alliance));
+	}
+
+	let now = now();
+	let mut batch = Batch::default();
+	for entity in entities {
+		let key = ActivityKey(entity);
+		let mut activity = key.get(tree)?.unwrap_or_default();
+		activity.hours[hour] += 1;
+		activity.weekdays[weekday] += 1;
+		activity.last_seen = now;
+		batch.insert(key.to_bytes()?, activity.to_bytes()?);
+	}
+
+	Ok(tree.apply_batch(batch)?)
+}
+
+/// The entity's activity profile. Only characters, corporations and alliances have one.
+pub fn get(tree: &Tree, entity: Entity) -> Result<Profile> {
+	let activity = ActivityKey(entity).get(tree)?.unwrap_or_default();
+
+	Ok(Profile {
+		involvements: activity.involvements(),
+		activity,
+		timezone: activity.timezone(),
+	})
+}
+
+/// Remove the activity of entities no killmail has involved for `days`.
+fn prune(tree: &Tree, days: u64) -> Result<usize> {
+	let oldest = now().saturating_sub(days * DAY);
+
+	let mut batch = Batch::default();
+	let mut removed = 0;
+	for entry in tree.iter() {
+		let (key, value) = entry?;
+		if Activity::from_bytes(&value)?.last_seen < oldest {
+			batch.remove(key);
+			removed += 1;
+		}
+	}
+
+	tree.apply_batch(batch)?;
+	Ok(removed)
+}
+
+/// Periodically remove the activity of entities that have gone quiet until shutdown.
+pub async fn run(state: State) {
+	let mut interval = interval(Duration::from_secs(60 * 60));
+	let mut shutdown = state.shutdown.clone();
+
+	loop {
+		select! {
+			_ = interval.tick() => {}
+			_ = stopped(&mut shutdown) => break,
+		}
+
+		match prune(&state.activity, state.config.retention.activity) {
+			Ok(0) => {}
+			Ok(removed) => info!("Removed the activity of {} inactive entities", removed),
+			Err(e) => warn!("Error removing inactive activity: {}", e),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::from_str;
+	use sled_ext::key::Key;
+
+	use super::{prune, record, slot, Activity, ActivityKey, MIN_INVOLVEMENTS};
+	use crate::{
+		model::zkb::{Killmail, EXAMPLE},
+		stats::Entity,
+		util::parse_timestamp,
+	};
+
+	fn activity(hours: &[(usize, u64)]) -> Activity {
+		let mut activity = Activity::default();
+		for (hour, count) in hours {
+			activity.hours[*hour] = *count;
+		}
+		activity
+	}
+
+	#[test]
+	fn estimates_timezones() {
+		let eu = activity(&[(19, 4), (20, 5), (21, 4), (3, 1)])
+			.timezone()
+			.unwrap();
+		assert_eq!((eu.peak_hour, eu.utc_offset, eu.label), (20, 0, "EUTZ"));
+
+		let au = activity(&[(9, 4), (10, 4), (11, 4)]).timezone().unwrap();
+		assert_eq!((au.peak_hour, au.utc_offset, au.label), (10, 10, "AUTZ"));
+	}
+
+	#[test]
+	fn finds_peaks_across_midnight() {
+		let us = activity(&[(23, 4), (0, 5), (1, 4), (12, 2)])
+			.timezone()
+			.unwrap();
+		assert_eq!((us.peak_hour, us.utc_offset, us.label), (0, -4, "USTZ"));
+
+		let late = activity(&[(22, 4), (23, 5), (0, 4)]).timezone().unwrap();
+		assert_eq!((late.peak_hour, late.utc_offset), (23, -3));
+	}
+
+	#[test]
+	fn needs_enough_involvements() {
+		let activity = activity(&[(20, MIN_INVOLVEMENTS - 1)]);
+		assert!(activity.timezone().is_none());
+	}
+
+	#[test]
+	fn slots_times() {
+		let time = |timestamp: &str| slot(parse_timestamp(timestamp).unwrap());
+
+		// the epoch was a Thursday
+		assert_eq!(slot(0), (0, 3));
+		assert_eq!(time("2021-10-28T04:51:31Z"), (4, 3));
+		assert_eq!(time("2021-10-31T23:59:59Z"), (23, 6));
+		assert_eq!(time("2021-11-01T00:00:00Z"), (0, 0));
+	}
+
+	#[test]
+	fn prunes_inactive_entities() {
+		let db = sled::Config::new().temporary(true).open().unwrap();
+		let tree = db.open_tree("activity").unwrap();
+
+		let km = from_str::<Killmail>(EXAMPLE).unwrap();
+		record(&tree, &km).unwrap();
+		let recorded = tree.len();
+
+		let quiet = ActivityKey(Entity::Character(1));
+		let last_seen = parse_timestamp("2021-10-28T04:51:31Z").unwrap();
+		quiet
+			.insert(
+				&tree,
+				Activity {
+					last_seen,
+					..Activity::default()
+				},
+			)
+			.unwrap();
+
+		assert_eq!(prune(&tree, 90).unwrap(), 1);
+		assert_eq!(tree.len(), recorded);
+		assert!(quiet.get(&tree).unwrap().is_none());
+	}
+}
@@ -0,0 +1,199 @@
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use serde_json::{from_str, to_string, to_string_pretty};
+use sled::Db;
+
+use crate::{
+	backup::{self, Backup, Mode},
+	config::Config,
+	index::{Entry, Index},
+	model::{Filter, Filters, Subscription},
+	storage,
+};
+
+const USAGE: &str = "\
+Usage: zkill-webhook admin <command>
+
+Commands:
+  list                          List every subscription with its ID and filters
+  add <filter> <subscription>   Add a subscription (both as JSON) to a filter
+  remove <webhook-url> [filter] Remove subscriptions posting to a URL, from one filter or all
+  search <text>                 List subscriptions whose webhook URL contains the text
+  formats                       Count subscriptions by format
+  compact                       Rewrite the database to reclaim space
+  export [file]                 Write every subscription as JSON to a file or stdout
+  import <file> [--replace]     Merge subscriptions from a JSON export, or replace all of them
+
+The server must be stopped, since sled allows only one process to open the database.";
+
+fn open(config: &Config) -> Result<(Db, Index)> {
+	let db = sled::open(&config.db.path)
+		.with_context(|| format!("Failed to open {}", config.db.path.display()))?;
+	storage::migrate(&db, config)?;
+
+	let index = Index::load(&db, &config.db.tree)?;
+	Ok((db, index))
+}
+
+fn print_entries(entries: &[Entry]) -> Result<()> {
+	for entry in entries {
+		println!("{}", to_string(entry)?);
+	}
+
+	Ok(())
+}
+
+fn add(index: &Index, filter: &str, sub: &str) -> Result<()> {
+	let filter = from_str::<Filter>(filter).context("Invalid filter")?;
+	filter.validate()?;
+	let sub = from_str::<Subscription>(sub).context("Invalid subscription")?;
+	sub.format.validate()?;
+
+	let mut filters = Filters::new();
+	filters.insert(filter, Some(sub).into_iter().collect());
+	index.subscribe(&filters).map(drop)
+}
+
+fn remove(index: &Index, webhook_url: &str, filter: Option<&str>) -> Result<()> {
+	let removed = match filter {
+		Some(filter) => {
+			let filter = from_str::<Filter>(filter).context("Invalid filter")?;
+			let matching = index
+				.entries()
+				.into_iter()
+				.filter(|entry| {
+					entry.subscription.webhook_url == webhook_url && entry.filters.contains(&filter)
+				})
+				.collect::<Vec<_>>();
+
+			for mut entry in matching.iter().cloned() {
+				entry.filters.remove(&filter);
+
+				// a subscription without filters would never match anything
+				if entry.filters.is_empty() {
+					index.remove(entry.id)?;
+				} else {
+					index.patch(entry.id, |_| {}, Some(entry.filters))?;
+				}
+			}
+
+			matching.len()
+		}
+		None => {
+			index.update(|sub| Some(sub.clone()).filter(|sub| sub.webhook_url != webhook_url))?
+		}
+	};
+
+	println!("Removed {} subscriptions", removed);
+	Ok(())
+}
+
+fn search(index: &Index, text: &str) -> Result<()> {
+	let matching = index
+		.entries()
+		.into_iter()
+		.filter(|entry| entry.subscription.webhook_url.contains(text))
+		.collect::<Vec<_>>();
+
+	print_entries(&matching)
+}
+
+fn formats(index: &Index) {
+	let mut counts = BTreeMap::<_, usize>::new();
+	for entry in index.entries() {
+		*counts.entry(entry.subscription.format.name()).or_default() += 1;
+	}
+
+	for (name, count) in counts {
+		println!("{}: {}", name, count);
+	}
+}
+
+/// Copy the database into a fresh directory and swap it into place, leaving behind the space
+/// sled hasn't reclaimed. The old directory is only deleted once the new one is in place.
+fn compact(config: &Config, db: Db) -> Result<()> {
+	let path = &config.db.path;
+	let compacted = PathBuf::from(format!("{}.compact", path.display()));
+	let old = PathBuf::from(format!("{}.old", path.display()));
+	let before = db.size_on_disk()?;
+
+	ensure!(
+		!old.exists(),
+		"{} exists from an interrupted compaction; restore or remove it first",
+		old.display()
+	);
+
+	// a copy left by an interrupted compaction is incomplete, and importing into it would mix it
+	// with this one
+	if compacted.exists() {
+		fs::remove_dir_all(&compacted)
+			.with_context(|| format!("Failed to remove {}", compacted.display()))?;
+	}
+
+	{
+		let new = sled::open(&compacted)?;
+		new.import(db.export());
+		new.flush()?;
+		println!(
+			"Compacted {} bytes into {} bytes",
+			before,
+			new.size_on_disk()?
+		);
+	}
+
+	drop(db);
+	fs::rename(path, &old)?;
+	if let Err(e) = fs::rename(&compacted, path) {
+		fs::rename(&old, path)?;
+		return Err(e).context("Failed to move the compacted database into place");
+	}
+	fs::remove_dir_all(&old)?;
+
+	Ok(())
+}
+
+fn export(index: &Index, path: Option<&str>) -> Result<()> {
+	let json = to_string_pretty(&backup::export(index))?;
+
+	match path {
+		Some(path) => fs::write(path, json)?,
+		None => println!("{}", json),
+	}
+
+	Ok(())
+}
+
+fn import(index: &Index, path: &str, mode: Mode) -> Result<()> {
+	let contents = fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+	let backup = from_str::<Backup>(&contents).context("Invalid export")?;
+	backup.validate()?;
+	backup::import(index, backup, mode)
+}
+
+/// Run an admin command against the database directly.
+pub fn run(config: &Config, args: &[String]) -> Result<()> {
+	let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+	let (db, index) = open(config)?;
+
+	match args.as_slice() {
+		["list"] => print_entries(&index.entries())?,
+		["add", filter, sub] => add(&index, filter, sub)?,
+		["remove", webhook_url] => remove(&index, webhook_url, None)?,
+		["remove", webhook_url, filter] => remove(&index, webhook_url, Some(*filter))?,
+		["search", text] => search(&index, text)?,
+		["formats"] => formats(&index),
+		["export"] => export(&index, None)?,
+		["export", path] => export(&index, Some(*path))?,
+		["import", path] => import(&index, path, Mode::Merge)?,
+		["import", path, "--replace"] => import(&index, path, Mode::Replace)?,
+		["compact"] => {
+			drop(index);
+			return compact(config, db);
+		}
+		_ => bail!("{}", USAGE),
+	}
+
+	db.flush()?;
+	Ok(())
+}
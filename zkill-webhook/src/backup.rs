@@ -0,0 +1,79 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	index::Index,
+	model::{Filter, Filters, Subscriptions},
+};
+
+/// Every filter and its subscriptions in a form that doesn't depend on the storage encoding.
+/// Subscription IDs aren't kept; imported subscriptions get new ones.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Backup {
+	pub filters: Vec<BackupEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupEntry {
+	pub filter: Filter,
+	pub subscriptions: Subscriptions,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+	/// Add the imported subscriptions to those already registered.
+	Merge,
+	/// Replace every registered subscription with the imported ones.
+	Replace,
+}
+
+impl Default for Mode {
+	fn default() -> Self {
+		Self::Merge
+	}
+}
+
+pub fn export(index: &Index) -> Backup {
+	Backup {
+		filters: index
+			.filters()
+			.into_iter()
+			.map(|(filter, subscriptions)| BackupEntry {
+				filter,
+				subscriptions,
+			})
+			.collect(),
+	}
+}
+
+impl Backup {
+	/// Check that every filter and format can be registered.
+	pub fn validate(&self) -> Result<()> {
+		for entry in &self.filters {
+			entry.filter.validate()?;
+
+			for sub in entry.subscriptions.iter() {
+				sub.format.validate()?;
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// Register the backed up subscriptions, which should have passed [`Backup::validate`].
+pub fn import(index: &Index, backup: Backup, mode: Mode) -> Result<()> {
+	let mut filters = Filters::new();
+	for entry in backup.filters {
+		filters
+			.entry(entry.filter)
+			.or_default()
+			.extend(entry.subscriptions.into_inner());
+	}
+
+	match mode {
+		Mode::Merge => index.subscribe(&filters).map(drop),
+		Mode::Replace => index.replace(filters),
+	}
+}
@@ -0,0 +1,641 @@
+use std::{
+	collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+	time::Duration,
+};
+
+use anyhow::{Error, Result};
+use futures::future::{join, join_all};
+use serde::{Deserialize, Serialize};
+use sled::{IVec, Tree};
+use sled_ext::{key::Key, value::Value};
+use tokio::{select, time::interval};
+use tracing::log::warn;
+
+use crate::{
+	delivery::post,
+	esi::Esi,
+	format::Payload,
+	model::{zkb::Killmail, SubscriptionId},
+	storage::{decode, encode},
+	util::{format_isk, now, parse_timestamp, stopped},
+	State,
+};
+
+/// Groups listed per side in a report's text before the rest are counted.
+const LISTED_GROUPS: usize = 5;
+
+/// Who a pilot fights for: their alliance, or their corporation if they have none.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub enum Group {
+	Alliance(usize),
+	Corporation(usize),
+}
+
+impl Group {
+	fn of(alliance: Option<usize>, corporation: usize) -> Self {
+		alliance.map_or(Self::Corporation(corporation), Self::Alliance)
+	}
+
+	fn id(&self) -> usize {
+		match self {
+			Self::Alliance(id) | Self::Corporation(id) => *id,
+		}
+	}
+}
+
+/// A battle in a system, identified by when it was first seen.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BattleKey {
+	pub system: usize,
+	pub started_at: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Loss {
+	pub killmail_id: usize,
+	pub group: Group,
+	pub ship_type_id: usize,
+	/// Unknown when zKillboard hasn't priced the kill.
+	pub value: Option<f64>,
+}
+
+/// Kills in one system, each within the configured gap of another.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Battle {
+	/// Killmail times of the earliest and latest kills.
+	pub started_at: u64,
+	pub last_kill_at: u64,
+	/// When a kill was last added, to tell when the battle is over.
+	pub updated_at: u64,
+	pub losses: Vec<Loss>,
+	/// Group of every character seen.
+	pub pilots: BTreeMap<usize, Group>,
+	/// Groups seen attacking the same victim.
+	pub allies: BTreeSet<(Group, Group)>,
+	/// Attacking groups and their victims' groups.
+	pub enemies: BTreeSet<(Group, Group)>,
+	/// Battle report subscriptions matching any of the kills.
+	pub subscribers: BTreeSet<SubscriptionId>,
+}
+
+impl Key for BattleKey {
+	type Value = Battle;
+
+	type Error = Error;
+
+	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
+		decode(bytes)
+	}
+
+	fn to_bytes(&self) -> Result<IVec, Self::Error> {
+		encode(self)
+	}
+}
+
+impl Value for Battle {
+	type Error = Error;
+
+	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
+		decode(bytes)
+	}
+
+	fn to_bytes(&self) -> Result<IVec, Self::Error> {
+		encode(self)
+	}
+}
+
+/// A group named for a report.
+#[derive(Debug, Serialize)]
+pub struct NamedGroup {
+	pub group: Group,
+	pub name: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Side {
+	pub groups: Vec<NamedGroup>,
+	pub pilots: usize,
+	pub isk_lost: f64,
+	/// Ships lost by ship class.
+	pub ships_lost: BTreeMap<String, usize>,
+}
+
+/// A finished battle, as delivered to subscriptions.
+#[derive(Debug, Serialize)]
+pub struct Report {
+	pub system: usize,
+	pub system_name: Option<String>,
+	pub started_at: u64,
+	pub ended_at: u64,
+	pub kills: usize,
+	pub total_value: f64,
+	pub sides: Vec<Side>,
+}
+
+impl Battle {
+	fn new(time: u64) -> Self {
+		Self {
+			started_at: time,
+			last_kill_at: time,
+			updated_at: now(),
+			losses: vec![],
+			pilots: BTreeMap::new(),
+			allies: BTreeSet::new(),
+			enemies: BTreeSet::new(),
+			subscribers: BTreeSet::new(),
+		}
+	}
+
+	fn add(&mut self, km: &Killmail, time: u64) {
+		self.started_at = self.started_at.min(time);
+		self.last_kill_at = self.last_kill_at.max(time);
+		self.updated_at = now();
+
+		let victim = &km.victim;
+		let victim_group = Group::of(victim.alliance_id, victim.corporation_id);
+		if let Some(character) = victim.character_id {
+			self.pilots.insert(character, victim_group);
+		}
+
+		self.losses.push(Loss {
+			killmail_id: km.killmail_id,
+			group: victim_group,
+			ship_type_id: victim.ship_type_id,
+			value: km.value(),
+		});
+
+		let mut attackers = BTreeSet::new();
+		for attacker in &km.attackers {
+			let group = match attacker.corporation_id {
+				Some(corporation) => Group::of(attacker.alliance_id, corporation),
+				None => continue,
+			};
+
+			attackers.insert(group);
+			if let Some(character) = attacker.character_id {
+				self.pilots.insert(character, group);
+			}
+		}
+
+		// linking every attacker to one of them is enough to put them all on the same side
+		let mut attackers = attackers.into_iter();
+		if let Some(first) = attackers.next() {
+			self.enemies.insert((first, victim_group));
+			self.allies.extend(attackers.map(|other| (first, other)));
+		}
+	}
+
+	fn groups(&self) -> BTreeSet<Group> {
+		let mut groups = self.pilots.values().copied().collect::<BTreeSet<_>>();
+		groups.extend(self.losses.iter().map(|loss| loss.group));
+		for (a, b) in self.allies.iter().chain(&self.enemies) {
+			groups.insert(*a);
+			groups.insert(*b);
+		}
+		groups
+	}
+
+	/// Split the groups into sides. Groups that attacked together are on the same side; the
+	/// largest side comes first, followed by everyone it fought, then anyone left over.
+	fn sides(&self) -> Vec<BTreeSet<Group>> {
+		fn root(parents: &mut HashMap<Group, Group>, group: Group) -> Group {
+			let parent = *parents.entry(group).or_insert(group);
+			if parent == group {
+				return group;
+			}
+
+			let root = root(parents, parent);
+			parents.insert(group, root);
+			root
+		}
+
+		let groups = self.groups();
+		let mut parents = HashMap::new();
+		for (a, b) in &self.allies {
+			let (a, b) = (root(&mut parents, *a), root(&mut parents, *b));
+			if a != b {
+				parents.insert(a, b);
+			}
+		}
+
+		let mut components = HashMap::<_, BTreeSet<_>>::new();
+		for group in groups {
+			components
+				.entry(root(&mut parents, group))
+				.or_default()
+				.insert(group);
+		}
+
+		let size = |component: &BTreeSet<Group>| {
+			let pilots = self
+				.pilots
+				.values()
+				.filter(|group| component.contains(group))
+				.count();
+			let losses = self
+				.losses
+				.iter()
+				.filter(|loss| component.contains(&loss.group))
+				.count();
+			pilots + losses
+		};
+
+		let mut components = components
+			.into_iter()
+			.map(|(_, component)| component)
+			.collect::<Vec<_>>();
+		components.sort_by_key(|component| (std::cmp::Reverse(size(component)), component.clone()));
+
+		let mut components = components.into_iter();
+		let first = match components.next() {
+			Some(first) => first,
+			None => return vec![],
+		};
+
+		let fought = |component: &BTreeSet<Group>| {
+			self.enemies.iter().any(|(a, b)| {
+				(first.contains(a) && component.contains(b))
+					|| (component.contains(a) && first.contains(b))
+			})
+		};
+		let (enemies, others): (Vec<_>, Vec<_>) = components.partition(fought);
+
+		let mut sides = vec![first];
+		sides.push(enemies.into_iter().flatten().collect());
+		sides.push(others.into_iter().flatten().collect());
+		sides.retain(|side| !side.is_empty());
+		sides
+	}
+}
+
+impl Report {
+	async fn new(esi: &Esi, system: usize, battle: &Battle) -> Self {
+		let sides = battle.sides();
+
+		let mut ids = sides
+			.iter()
+			.flatten()
+			.map(Group::id)
+			.collect::<HashSet<_>>();
+		ids.insert(system);
+
+		let ships = battle
+			.losses
+			.iter()
+			.map(|loss| loss.ship_type_id)
+			.collect::<HashSet<_>>();
+		let classes = ships.into_iter().map(|ship| async move {
+			let class = match esi.item_type(ship).await {
+				Ok(item_type) => item_type.group,
+				Err(e) => {
+					warn!("Unable to look up ship type {}: {}", ship, e);
+					format!("Type {}", ship)
+				}
+			};
+			(ship, class)
+		});
+
+		let (names, classes) = join(esi.names(ids), join_all(classes)).await;
+		let names = names.unwrap_or_else(|e| {
+			warn!("Unable to resolve names for battle report: {}", e);
+			HashMap::new()
+		});
+		let classes = classes.into_iter().collect::<HashMap<_, _>>();
+
+		let sides = sides
+			.into_iter()
+			.map(|groups| {
+				let mut side = Side {
+					groups: vec![],
+					pilots: battle
+						.pilots
+						.values()
+						.filter(|group| groups.contains(group))
+						.count(),
+					isk_lost: 0.0,
+					ships_lost: BTreeMap::new(),
+				};
+
+				for loss in battle
+					.losses
+					.iter()
+					.filter(|loss| groups.contains(&loss.group))
+				{
+					side.isk_lost += loss.value.unwrap_or_default();
+					*side
+						.ships_lost
+						.entry(classes[&loss.ship_type_id].clone())
+						.or_default() += 1;
+				}
+
+				side.groups = groups
+					.into_iter()
+					.map(|group| NamedGroup {
+						group,
+						name: names.get(&group.id()).cloned(),
+					})
+					.collect();
+				side
+			})
+			.collect();
+
+		Self {
+			system,
+			system_name: names.get(&system).cloned(),
+			started_at: battle.started_at,
+			ended_at: battle.last_kill_at,
+			kills: battle.losses.len(),
+			total_value: battle.losses.iter().filter_map(|loss| loss.value).sum(),
+			sides,
+		}
+	}
+
+	pub fn text(&self) -> String {
+		let system = self
+			.system_name
+			.clone()
+			.unwrap_or_else(|| self.system.to_string());
+
+		let mut text = format!(
+			"**Battle in {}**: {} ships destroyed over {} minutes, {} ISK lost",
+			system,
+			self.kills,
+			(self.ended_at - self.started_at) / 60,
+			format_isk(self.total_value)
+		);
+
+		for (i, side) in self.sides.iter().enumerate() {
+			let mut groups = side
+				.groups
+				.iter()
+				.take(LISTED_GROUPS)
+				.map(|group| {
+					group
+						.name
+						.clone()
+						.unwrap_or_else(|| group.group.id().to_string())
+				})
+				.collect::<Vec<_>>();
+			if side.groups.len() > LISTED_GROUPS {
+				groups.push(format!("{} more", side.groups.len() - LISTED_GROUPS));
+			}
+
+			text.push_str(&format!(
+				"\n\n**Side {}** ({} pilots): {}\nLost {} ISK",
+				i + 1,
+				side.pilots,
+				groups.join(", "),
+				format_isk(side.isk_lost)
+			));
+
+			for (class, count) in &side.ships_lost {
+				text.push_str(&format!("\n{} x{}", class, count));
+			}
+		}
+
+		text
+	}
+}
+
+/// Add the killmail to the battle in its system, starting one if none has a kill within the gap.
+pub fn record(state: &State, km: &Killmail, subscribers: &[SubscriptionId]) -> Result<()> {
+	add(
+		&state.battles,
+		state.config.battles.gap * 60,
+		km,
+		subscribers,
+	)
+}
+
+/// [`record`] the killmail in the tree, given the gap in seconds.
+fn add(tree: &Tree, gap: u64, km: &Killmail, subscribers: &[SubscriptionId]) -> Result<()> {
+	let time = parse_timestamp(&km.killmail_time).unwrap_or_else(now);
+
+	// keys start with the system, so this finds its battles
+	let mut found = None;
+	for entry in tree.scan_prefix(encode(&km.solar_system_id)?) {
+		let (key, value) = entry?;
+		let battle = Battle::from_bytes(&value)?;
+
+		if time + gap >= battle.started_at && time <= battle.last_kill_at + gap {
+			found = Some((BattleKey::from_bytes(&key)?, battle));
+			break;
+		}
+	}
+
+	let (key, mut battle) = found.unwrap_or_else(|| {
+		let key = BattleKey {
+			system: km.solar_system_id,
+			started_at: time,
+		};
+		(key, Battle::new(time))
+	});
+
+	battle.add(km, time);
+	battle.subscribers.extend(subscribers);
+	key.insert(tree, battle)?;
+
+	Ok(())
+}
+
+async fn flush(state: &State) -> Result<()> {
+	let config = &state.config.battles;
+
+	for entry in state.battles.iter() {
+		let (key, value) = entry?;
+		let battle = Battle::from_bytes(&value)?;
+
+		if battle.updated_at + config.gap * 60 > now() {
+			continue;
+		}
+
+		// a kill added since iterating means the battle isn't over
+		if state
+			.battles
+			.compare_and_swap(&key, Some(&value), None::<IVec>)?
+			.is_err()
+		{
+			continue;
+		}
+
+		if battle.losses.len() < config.min_kills || battle.subscribers.is_empty() {
+			continue;
+		}
+
+		let system = BattleKey::from_bytes(&key)?.system;
+		let report = Report::new(&state.esi, system, &battle).await;
+		let now = now();
+
+		for id in &battle.subscribers {
+			let sub = match state.index.subscription(*id) {
+				Some(sub) if sub.is_active(now) => sub,
+				_ => continue,
+			};
+
+			// one failing subscriber shouldn't cost the others their report
+			match post(state, *id, sub, Payload::Battle(&report)).await {
+				Ok(Some(_)) => {
+					state.index.remove(*id)?;
+				}
+				Ok(None) => {}
+				Err(e) => warn!("Error reporting a battle to subscription {}: {}", id.0, e),
+			}
+		}
+	}
+
+	Ok(())
+}
+
+/// Periodically report battles that have ended until shutdown.
+pub async fn run(state: State) {
+	let mut interval = interval(Duration::from_secs(60));
+	let mut shutdown = state.shutdown.clone();
+
+	loop {
+		select! {
+			_ = interval.tick() => {}
+			_ = stopped(&mut shutdown) => break,
+		}
+
+		if let Err(e) = flush(&state).await {
+			warn!("Error reporting battles: {}", e);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeSet;
+
+	use serde_json::{from_str, from_value, json, Value};
+	use sled::Tree;
+	use sled_ext::value::Value as _;
+
+	use super::{add, Battle, Group};
+	use crate::model::zkb::{Killmail, EXAMPLE};
+
+	/// A pilot as their character, alliance and corporation.
+	type Pilot = (usize, Option<usize>, usize);
+
+	const GAP: u64 = 15 * 60;
+
+	fn killmail(
+		id: usize,
+		system: usize,
+		time: &str,
+		victim: Pilot,
+		attackers: &[Pilot],
+	) -> Killmail {
+		let mut km = from_str::<Value>(EXAMPLE).unwrap();
+		km["killmail_id"] = json!(id);
+		km["solar_system_id"] = json!(system);
+		km["killmail_time"] = json!(time);
+
+		let (character, alliance, corporation) = victim;
+		km["victim"]["character_id"] = json!(character);
+		km["victim"]["alliance_id"] = json!(alliance);
+		km["victim"]["corporation_id"] = json!(corporation);
+
+		km["attackers"] = attackers
+			.iter()
+			.map(|(character, alliance, corporation)| {
+				json!({
+					"character_id": character,
+					"alliance_id": alliance,
+					"corporation_id": corporation,
+					"damage_done": 100,
+					"final_blow": false,
+					"security_status": 0.0,
+				})
+			})
+			.collect();
+
+		from_value(km).unwrap()
+	}
+
+	/// Every battle, earliest first.
+	fn battles(tree: &Tree) -> Vec<Battle> {
+		let mut battles = tree
+			.iter()
+			.values()
+			.map(|value| Battle::from_bytes(&value.unwrap()).unwrap())
+			.collect::<Vec<_>>();
+		battles.sort_by_key(|battle| battle.started_at);
+		battles
+	}
+
+	#[test]
+	fn clusters_kills_within_the_gap() {
+		let db = sled::Config::new().temporary(true).open().unwrap();
+		let tree = db.open_tree("battles").unwrap();
+		let victim = (1, None, 10);
+		let attackers = [(2, None, 20)];
+
+		for (id, system, time) in &[
+			(1, 30_000_142, "2021-10-28T12:00:00Z"),
+			(2, 30_000_142, "2021-10-28T12:10:00Z"),
+			// kills can arrive out of order
+			(3, 30_000_142, "2021-10-28T11:50:00Z"),
+			(4, 30_000_142, "2021-10-28T12:40:00Z"),
+			(5, 30_000_144, "2021-10-28T12:05:00Z"),
+		] {
+			let km = killmail(*id, *system, time, victim, &attackers);
+			add(&tree, GAP, &km, &[]).unwrap();
+		}
+
+		let losses = battles(&tree)
+			.iter()
+			.map(|battle| {
+				battle
+					.losses
+					.iter()
+					.map(|loss| loss.killmail_id)
+					.collect::<Vec<_>>()
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(losses, vec![vec![1, 2, 3], vec![5], vec![4]]);
+
+		let first = &battles(&tree)[0];
+		assert_eq!(first.last_kill_at - first.started_at, 20 * 60);
+	}
+
+	#[test]
+	fn splits_sides() {
+		let db = sled::Config::new().temporary(true).open().unwrap();
+		let tree = db.open_tree("battles").unwrap();
+		let time = "2021-10-28T12:00:00Z";
+
+		// two alliances fighting two corporations, next to an unrelated fight
+		let kills = vec![
+			killmail(
+				1,
+				30_000_142,
+				time,
+				(30, None, 3),
+				&[(10, Some(1), 100), (12, Some(1), 100), (20, Some(2), 200)],
+			),
+			killmail(
+				2,
+				30_000_142,
+				time,
+				(11, Some(1), 100),
+				&[(31, None, 3), (40, None, 4)],
+			),
+			killmail(3, 30_000_142, time, (50, None, 5), &[(60, None, 6)]),
+		];
+		for km in &kills {
+			add(&tree, GAP, km, &[]).unwrap();
+		}
+
+		let battle = battles(&tree).remove(0);
+		let side = |groups: &[Group]| groups.iter().copied().collect::<BTreeSet<_>>();
+		assert_eq!(
+			battle.sides(),
+			vec![
+				side(&[Group::Alliance(1), Group::Alliance(2)]),
+				side(&[Group::Corporation(3), Group::Corporation(4)]),
+				side(&[Group::Corporation(5), Group::Corporation(6)]),
+			]
+		);
+	}
+}
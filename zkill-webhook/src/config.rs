@@ -0,0 +1,452 @@
+use std::{
+	env,
+	fmt::Display,
+	fs,
+	net::SocketAddr,
+	path::{Path, PathBuf},
+	str::FromStr,
+	time::Duration,
+};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Where the configuration file is read from unless `ZKILL_CONFIG` says otherwise.
+const DEFAULT_PATH: &str = "config.toml";
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	/// Address the HTTP server listens on.
+	pub listen: SocketAddr,
+	pub db: DbConfig,
+	pub source: SourceConfig,
+	pub delivery: DeliveryConfig,
+	pub retention: RetentionConfig,
+	pub admin: AdminConfig,
+	pub stream: StreamConfig,
+	pub esi: EsiConfig,
+	pub stats: StatsConfig,
+	pub battles: BattleConfig,
+	pub notable: NotableConfig,
+	pub activity: ActivityConfig,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DbConfig {
+	/// Directory of the sled database.
+	pub path: PathBuf,
+	/// Name of the tree holding filters and their subscriptions.
+	pub tree: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourceConfig {
+	/// zKillboard websocket URL.
+	pub url: String,
+	/// Message sent after connecting to subscribe to every killmail.
+	pub subscribe: String,
+	/// Most of zKillboard's narrower channels to subscribe to when no filter needs every
+	/// killmail. Zero always subscribes to every killmail, as does enabling stats or activity.
+	pub max_channels: usize,
+	/// Replay killmails from this newline-delimited JSON file instead of connecting to zKillboard.
+	pub replay: Option<PathBuf>,
+	/// Space out replayed killmails as far apart as they happened.
+	pub pacing: bool,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeliveryConfig {
+	/// Seconds to wait for a webhook to respond.
+	pub timeout: u64,
+	/// Seconds to wait for a connection to a webhook.
+	pub connect_timeout: u64,
+	/// Deliveries that can wait for a worker before ingestion is paused.
+	pub queue_size: usize,
+	/// Deliveries that can be in flight at once.
+	pub concurrency: usize,
+	/// Seconds to wait for ingestion, queued deliveries and background tasks to stop when shutting
+	/// down.
+	pub shutdown_timeout: u64,
+	/// Consecutive failed deliveries after which a subscription is removed.
+	pub max_failures: u64,
+}
+
+/// How much history is kept. Daily stats aren't covered: they're kept for as long as the monthly
+/// window they feed, and removed once they leave it.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+	/// Most valuable kills kept in a pending digest for its top kills; totals still count every
+	/// kill.
+	pub digest_kills: usize,
+	/// Days the delivery log of a removed subscription, such as a dead letter, is kept after its
+	/// last delivery.
+	pub delivery_logs: u64,
+	/// Days an entity's activity is kept after a killmail involving it was last recorded.
+	pub activity: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StreamConfig {
+	/// Killmails buffered for each streaming client before it starts missing them.
+	pub buffer: usize,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct EsiConfig {
+	/// Base URL of the ESI API.
+	pub url: String,
+	/// Seconds a resolved name is cached before it's looked up again.
+	pub name_ttl: u64,
+	/// Seconds to wait for ESI to respond. Names are resolved before a killmail is delivered, so
+	/// this holds up deliveries while ESI is slow.
+	pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsConfig {
+	/// Count kills and losses per character, corporation, alliance, system and ship. Off by
+	/// default: counting needs every killmail, so it keeps the source on the killstream instead of
+	/// the channels `source.max_channels` narrows it to.
+	pub enabled: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ActivityConfig {
+	/// Count when each character, corporation and alliance is active. Off by default, since like
+	/// stats it keeps the source on the killstream.
+	pub enabled: bool,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct BattleConfig {
+	/// Group kills into battles and report them to subscriptions that asked for battles.
+	pub enabled: bool,
+	/// Minutes without a kill in a system before its battle is over.
+	pub gap: u64,
+	/// Fewest kills a battle needs to be reported.
+	pub min_kills: usize,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotableConfig {
+	/// Rank each kill's value against recent kills of the same ship group for notable filters.
+	pub enabled: bool,
+	/// Latest kills of each ship group kept to rank against.
+	pub samples: usize,
+	/// Kills a ship group needs before its kills are ranked.
+	pub min_samples: usize,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+	/// Bearer token required by the admin endpoints, which are disabled when unset.
+	pub token: Option<String>,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Self {
+			listen: SocketAddr::from(([0, 0, 0, 0], 3000)),
+			db: DbConfig::default(),
+			source: SourceConfig::default(),
+			delivery: DeliveryConfig::default(),
+			retention: RetentionConfig::default(),
+			admin: AdminConfig::default(),
+			stream: StreamConfig::default(),
+			esi: EsiConfig::default(),
+			stats: StatsConfig::default(),
+			battles: BattleConfig::default(),
+			notable: NotableConfig::default(),
+			activity: ActivityConfig::default(),
+		}
+	}
+}
+
+impl Default for DbConfig {
+	fn default() -> Self {
+		Self {
+			path: "data".into(),
+			tree: "webhooks".into(),
+		}
+	}
+}
+
+impl Default for SourceConfig {
+	fn default() -> Self {
+		Self {
+			url: "wss://zkillboard.com/websocket/".into(),
+			subscribe: r#"{"action":"sub","channel":"killstream"}"#.into(),
+			max_channels: 500,
+			replay: None,
+			pacing: false,
+		}
+	}
+}
+
+impl Default for DeliveryConfig {
+	fn default() -> Self {
+		Self {
+			timeout: 10,
+			connect_timeout: 5,
+			queue_size: 1024,
+			concurrency: 64,
+			shutdown_timeout: 30,
+			max_failures: 5,
+		}
+	}
+}
+
+impl Default for StreamConfig {
+	fn default() -> Self {
+		Self { buffer: 256 }
+	}
+}
+
+impl Default for EsiConfig {
+	fn default() -> Self {
+		Self {
+			url: "https://esi.evetech.net/latest".into(),
+			name_ttl: 7 * 24 * 60 * 60,
+			timeout: 5,
+		}
+	}
+}
+
+impl Default for BattleConfig {
+	fn default() -> Self {
+		Self {
+			enabled: true,
+			gap: 10,
+			min_kills: 10,
+		}
+	}
+}
+
+impl Default for NotableConfig {
+	fn default() -> Self {
+		Self {
+			enabled: true,
+			samples: 1000,
+			min_samples: 50,
+		}
+	}
+}
+
+impl Default for RetentionConfig {
+	fn default() -> Self {
+		Self {
+			digest_kills: 100,
+			delivery_logs: 30,
+			activity: 90,
+		}
+	}
+}
+
+/// Replace `target` with the parsed value of the environment variable, if it's set.
+fn var<T>(key: &str, target: &mut T) -> Result<()>
+where
+	T: FromStr,
+	T::Err: Display,
+{
+	if let Ok(value) = env::var(key) {
+		*target = value
+			.parse()
+			.map_err(|e| anyhow!("Invalid value for {}: {}", key, e))?;
+	}
+
+	Ok(())
+}
+
+impl Config {
+	/// Load the configuration file, if present, apply environment overrides and validate the
+	/// result.
+	pub fn load() -> Result<Self> {
+		let path = env::var("ZKILL_CONFIG").ok();
+		let mut config = match &path {
+			Some(path) => Self::from_file(path)?,
+			None if Path::new(DEFAULT_PATH).exists() => Self::from_file(DEFAULT_PATH)?,
+			None => Self::default(),
+		};
+
+		config.override_from_env()?;
+		config.validate()?;
+		Ok(config)
+	}
+
+	fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+		let path = path.as_ref();
+		let contents = fs::read_to_string(path)
+			.with_context(|| format!("Failed to read config from {}", path.display()))?;
+
+		toml::from_str(&contents)
+			.with_context(|| format!("Failed to parse config from {}", path.display()))
+	}
+
+	fn override_from_env(&mut self) -> Result<()> {
+		if let Ok(port) = env::var("PORT") {
+			self.listen
+				.set_port(port.parse().context("Invalid value for PORT")?);
+		}
+
+		var("ZKILL_LISTEN", &mut self.listen)?;
+		var("ZKILL_DB_PATH", &mut self.db.path)?;
+		var("ZKILL_DB_TREE", &mut self.db.tree)?;
+		var("ZKILL_SOURCE_URL", &mut self.source.url)?;
+		var("ZKILL_SOURCE_SUBSCRIBE", &mut self.source.subscribe)?;
+		var("ZKILL_SOURCE_PACING", &mut self.source.pacing)?;
+		var("ZKILL_SOURCE_MAX_CHANNELS", &mut self.source.max_channels)?;
+		if let Ok(path) = env::var("ZKILL_SOURCE_REPLAY") {
+			self.source.replay = Some(path.into());
+		}
+		var("ZKILL_DELIVERY_TIMEOUT", &mut self.delivery.timeout)?;
+		var(
+			"ZKILL_DELIVERY_CONNECT_TIMEOUT",
+			&mut self.delivery.connect_timeout,
+		)?;
+		var("ZKILL_DELIVERY_QUEUE_SIZE", &mut self.delivery.queue_size)?;
+		var("ZKILL_DELIVERY_CONCURRENCY", &mut self.delivery.concurrency)?;
+		var(
+			"ZKILL_DELIVERY_SHUTDOWN_TIMEOUT",
+			&mut self.delivery.shutdown_timeout,
+		)?;
+		var(
+			"ZKILL_DELIVERY_MAX_FAILURES",
+			&mut self.delivery.max_failures,
+		)?;
+		var(
+			"ZKILL_RETENTION_DIGEST_KILLS",
+			&mut self.retention.digest_kills,
+		)?;
+		var(
+			"ZKILL_RETENTION_DELIVERY_LOGS",
+			&mut self.retention.delivery_logs,
+		)?;
+		var("ZKILL_RETENTION_ACTIVITY", &mut self.retention.activity)?;
+
+		var("ZKILL_STREAM_BUFFER", &mut self.stream.buffer)?;
+		var("ZKILL_ESI_URL", &mut self.esi.url)?;
+		var("ZKILL_ESI_NAME_TTL", &mut self.esi.name_ttl)?;
+		var("ZKILL_ESI_TIMEOUT", &mut self.esi.timeout)?;
+		var("ZKILL_STATS_ENABLED", &mut self.stats.enabled)?;
+		var("ZKILL_BATTLES_ENABLED", &mut self.battles.enabled)?;
+		var("ZKILL_BATTLES_GAP", &mut self.battles.gap)?;
+		var("ZKILL_BATTLES_MIN_KILLS", &mut self.battles.min_kills)?;
+		var("ZKILL_NOTABLE_ENABLED", &mut self.notable.enabled)?;
+		var("ZKILL_NOTABLE_SAMPLES", &mut self.notable.samples)?;
+		var("ZKILL_NOTABLE_MIN_SAMPLES", &mut self.notable.min_samples)?;
+		var("ZKILL_ACTIVITY_ENABLED", &mut self.activity.enabled)?;
+
+		if let Ok(token) = env::var("ZKILL_ADMIN_TOKEN") {
+			self.admin.token = Some(token);
+		}
+
+		Ok(())
+	}
+
+	fn validate(&self) -> Result<()> {
+		ensure!(!self.db.tree.is_empty(), "db.tree must not be empty");
+		ensure!(
+			self.source.replay.is_some()
+				|| self.source.url.starts_with("ws://")
+				|| self.source.url.starts_with("wss://"),
+			"source.url must be a ws:// or wss:// URL"
+		);
+		serde_json::from_str::<Value>(&self.source.subscribe)
+			.context("source.subscribe must be JSON")?;
+		ensure!(
+			self.delivery.timeout > 0,
+			"delivery.timeout must be positive"
+		);
+		ensure!(
+			self.delivery.connect_timeout > 0,
+			"delivery.connect_timeout must be positive"
+		);
+		ensure!(
+			self.delivery.queue_size > 0,
+			"delivery.queue_size must be positive"
+		);
+		ensure!(
+			self.delivery.concurrency > 0,
+			"delivery.concurrency must be positive"
+		);
+		ensure!(
+			self.delivery.max_failures > 0,
+			"delivery.max_failures must be positive"
+		);
+		ensure!(
+			self.retention.digest_kills > 0,
+			"retention.digest_kills must be positive"
+		);
+		ensure!(
+			self.retention.delivery_logs > 0,
+			"retention.delivery_logs must be positive"
+		);
+		ensure!(
+			self.retention.activity > 0,
+			"retention.activity must be positive"
+		);
+		ensure!(self.stream.buffer > 0, "stream.buffer must be positive");
+		ensure!(
+			self.esi.url.starts_with("http://") || self.esi.url.starts_with("https://"),
+			"esi.url must be an http:// or https:// URL"
+		);
+		ensure!(self.esi.timeout > 0, "esi.timeout must be positive");
+		ensure!(self.battles.gap > 0, "battles.gap must be positive");
+		ensure!(
+			self.notable.min_samples > 0,
+			"notable.min_samples must be positive"
+		);
+		ensure!(
+			self.notable.samples >= self.notable.min_samples,
+			"notable.samples must be at least notable.min_samples"
+		);
+		ensure!(
+			self.admin
+				.token
+				.as_ref()
+				.map_or(true, |token| !token.is_empty()),
+			"admin.token must not be empty"
+		);
+
+		Ok(())
+	}
+
+	/// Whether the source has to stay on the killstream whatever the filters need, because stats
+	/// or activity count kills no filter matches.
+	pub fn killstream(&self) -> bool {
+		self.stats.enabled || self.activity.enabled
+	}
+}
+
+impl DeliveryConfig {
+	pub fn timeout(&self) -> Duration {
+		Duration::from_secs(self.timeout)
+	}
+
+	pub fn connect_timeout(&self) -> Duration {
+		Duration::from_secs(self.connect_timeout)
+	}
+
+	pub fn shutdown_timeout(&self) -> Duration {
+		Duration::from_secs(self.shutdown_timeout)
+	}
+}
+
+impl EsiConfig {
+	pub fn timeout(&self) -> Duration {
+		Duration::from_secs(self.timeout)
+	}
+}
@@ -0,0 +1,279 @@
+use std::{
+	collections::{HashMap, HashSet},
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+};
+
+use crate::{
+	activity, battle,
+	delivery_log::{self, Failure},
+	digest,
+	format::Payload,
+	model::{zkb::Killmail, Filter, Subscription, SubscriptionId},
+	notable, stats,
+	util::now,
+	State,
+};
+use anyhow::{anyhow, Result};
+use tokio::{
+	select, spawn,
+	sync::{
+		mpsc::{error::TrySendError, Receiver, Sender},
+		watch, Semaphore,
+	},
+};
+use tracing::{
+	debug,
+	log::{info, warn},
+};
+
+/// A killmail waiting to be delivered to one subscription.
+#[derive(Debug)]
+pub struct Delivery {
+	id: SubscriptionId,
+	sub: Subscription,
+	km: Arc<Killmail>,
+}
+
+/// Send a message to the subscription with the killmail contents. Returns the subscription if it
+/// should be removed for failing.
+async fn send_message(
+	state: State,
+	id: SubscriptionId,
+	sub: Subscription,
+	km: impl AsRef<Killmail>,
+) -> Result<Option<Subscription>> {
+	let outcome = sub.classify(km.as_ref());
+
+	if sub.digest.is_some() {
+		digest::record(&state, id, km.as_ref(), outcome)?;
+		return Ok(None);
+	}
+
+	post(&state, id, sub, Payload::Killmail(km.as_ref(), outcome)).await
+}
+
+/// Deliver a payload to the subscription's webhook. Returns the subscription if it failed too many
+/// times in a row and should be removed.
+pub async fn post(
+	state: &State,
+	id: SubscriptionId,
+	sub: Subscription,
+	payload: Payload<'_>,
+) -> Result<Option<Subscription>> {
+	let res = sub
+		.format
+		.request(&state.client, &sub.webhook_url, payload)?
+		.send()
+		.await;
+
+	let failure = match res {
+		Ok(response) if response.status().is_success() => None,
+		Ok(response) => {
+			let status = response.status();
+			let body = response.text().await.unwrap_or_default();
+			Some(Failure::new(Some(status.as_u16()), &body))
+		}
+		Err(e) => Some(Failure::new(
+			e.status().map(|status| status.as_u16()),
+			&e.to_string(),
+		)),
+	};
+
+	match failure {
+		Some(failure) => {
+			warn!(
+				"Error posting to webhook ({:?}: {})",
+				failure.status, failure.body
+			);
+
+			let max_failures = state.config.delivery.max_failures;
+			if delivery_log::record_failure(&state.delivery_log, id, &sub, failure, max_failures)? {
+				warn!(
+					"Removing subscription {} after {} consecutive failures",
+					id.0, max_failures
+				);
+				Ok(Some(sub))
+			} else {
+				Ok(None)
+			}
+		}
+		None => {
+			delivery_log::record_success(&state.delivery_log, id, &sub)?;
+			Ok(None)
+		}
+	}
+}
+
+async fn deliver(state: State, delivery: Delivery) -> Result<()> {
+	let Delivery { id, sub, km } = delivery;
+
+	if send_message(state.clone(), id, sub, km).await?.is_some() {
+		state.index.remove(id)?;
+	}
+
+	Ok(())
+}
+
+/// Whether the delivery queue was last found full, so that's only logged when it changes.
+static QUEUE_FULL: AtomicBool = AtomicBool::new(false);
+
+/// Queue a delivery, waiting for space if the queue is full.
+async fn enqueue(queue: &Sender<Delivery>, delivery: Delivery) -> Result<()> {
+	match queue.try_send(delivery) {
+		Ok(()) => {
+			if QUEUE_FULL.swap(false, Ordering::Relaxed) {
+				info!("Delivery queue has recovered");
+			}
+			Ok(())
+		}
+		Err(TrySendError::Full(delivery)) => {
+			if !QUEUE_FULL.swap(true, Ordering::Relaxed) {
+				warn!("Delivery queue is full; applying backpressure to ingestion");
+			}
+			queue
+				.send(delivery)
+				.await
+				.map_err(|_| anyhow!("Delivery queue is closed"))
+		}
+		Err(TrySendError::Closed(_)) => Err(anyhow!("Delivery queue is closed")),
+	}
+}
+
+/// Queue one delivery of the killmail to each matching subscription, resolving its names first if
+/// anyone will see it. Subscriptions to battle reports get the killmail added to its battle
+/// instead.
+pub async fn process_killmail(state: &State, mut km: Killmail) -> Result<()> {
+	debug!("Received killmail: {:?}", km);
+
+	if state.config.stats.enabled {
+		if let Err(e) = stats::record(&state.stats, &km) {
+			warn!(
+				"Error recording stats for killmail {}: {}",
+				km.killmail_id, e
+			);
+		}
+	}
+
+	if state.config.activity.enabled {
+		if let Err(e) = activity::record(&state.activity, &km) {
+			warn!(
+				"Error recording activity for killmail {}: {}",
+				km.killmail_id, e
+			);
+		}
+	}
+
+	// notable filters keep the websocket on the killstream, so baselines see every kill
+	let notable = |filter: &Filter| matches!(filter, Filter::Notable { .. });
+	if state.config.notable.enabled && state.index.wants(notable) {
+		match notable::record(state, &km) {
+			Ok(percentile) => km.percentile = percentile,
+			Err(e) => warn!(
+				"Unable to rank the value of killmail {}: {}",
+				km.killmail_id, e
+			),
+		}
+	}
+
+	let now = now();
+	let mut deliveries = HashMap::new();
+	let mut battles = HashSet::new();
+	for filter in km.filters() {
+		for (id, sub) in state.index.get(&filter) {
+			if !sub.is_active(now) {
+				continue;
+			}
+
+			// battle subscriptions hear about the battle once it's over instead
+			if sub.battles {
+				battles.insert(id);
+			} else {
+				deliveries.insert(id, sub);
+			}
+		}
+	}
+
+	if state.config.battles.enabled {
+		let subscribers = battles.into_iter().collect::<Vec<_>>();
+		if let Err(e) = battle::record(state, &km, &subscribers) {
+			warn!(
+				"Error recording killmail {} in a battle: {}",
+				km.killmail_id, e
+			);
+		}
+	}
+
+	if deliveries.is_empty() && state.killmails.receiver_count() == 0 {
+		return Ok(());
+	}
+
+	if let Err(e) = state.esi.enrich(&mut km).await {
+		warn!(
+			"Unable to resolve names for killmail {}: {}",
+			km.killmail_id, e
+		);
+	}
+
+	let km = Arc::new(km);
+
+	// there are no streams to send to when nobody is connected
+	let _ = state.killmails.send(Arc::clone(&km));
+
+	for (id, sub) in deliveries {
+		let delivery = Delivery {
+			id,
+			sub,
+			km: Arc::clone(&km),
+		};
+
+		enqueue(&state.queue, delivery).await?;
+	}
+
+	Ok(())
+}
+
+/// Work through queued deliveries, running at most the configured number at once. Once `drain`
+/// fires, no more deliveries are accepted and this returns after the queue has drained and
+/// in-flight deliveries have finished. Only fire it after ingestion has stopped, or killmails it
+/// is still processing will be lost.
+pub async fn run(state: State, mut queue: Receiver<Delivery>, mut drain: watch::Receiver<bool>) {
+	let concurrency = state.config.delivery.concurrency;
+	let permits = Arc::new(Semaphore::new(concurrency));
+	let mut closing = false;
+
+	loop {
+		let permit = Arc::clone(&permits)
+			.acquire_owned()
+			.await
+			.expect("delivery semaphore is never closed");
+
+		let delivery = select! {
+			delivery = queue.recv() => delivery,
+			_ = drain.changed(), if !closing => {
+				debug!("Closing delivery queue");
+				queue.close();
+				closing = true;
+				continue;
+			}
+		};
+
+		let delivery = match delivery {
+			Some(delivery) => delivery,
+			None => break,
+		};
+
+		let state = state.clone();
+		spawn(async move {
+			if let Err(e) = deliver(state, delivery).await {
+				warn!("Error delivering killmail: {}", e);
+			}
+
+			drop(permit);
+		});
+	}
+
+	let _ = permits.acquire_many(concurrency as u32).await;
+}
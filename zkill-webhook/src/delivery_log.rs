@@ -0,0 +1,307 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Error, Result};
+use serde::{Deserialize, Serialize};
+use sled::{Batch, IVec, Tree};
+use sled_ext::{key::Key, value::Value};
+use tokio::{select, time::interval};
+use tracing::log::{info, warn};
+
+use crate::{
+	index::Index,
+	model::{Subscription, SubscriptionId},
+	storage::{decode, encode},
+	util::{now, stopped},
+	State,
+};
+
+/// How much of a failed response body to keep.
+const SNIPPET_LEN: usize = 256;
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// Key of a subscription's delivery log.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogKey(pub SubscriptionId);
+
+/// Delivery history of a subscription. Since subscriptions are removed after too many consecutive
+/// failures, a log with `removed_at` set is the dead letter record of that subscription.
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct DeliveryLog {
+	pub total_deliveries: u64,
+	pub total_failures: u64,
+	/// Failures since the last successful delivery.
+	pub consecutive_failures: u64,
+	pub last_success: Option<u64>,
+	pub last_failure: Option<Failure>,
+	/// When the subscription was removed for failing.
+	pub removed_at: Option<u64>,
+}
+
+impl DeliveryLog {
+	/// When anything was last delivered or failed to be.
+	fn last_event(&self) -> u64 {
+		self.last_success
+			.into_iter()
+			.chain(self.last_failure.as_ref().map(|failure| failure.at))
+			.chain(self.removed_at)
+			.max()
+			.unwrap_or_default()
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Failure {
+	pub at: u64,
+	/// HTTP status of the response, if one was received.
+	pub status: Option<u16>,
+	/// The start of the response body, or the error if no response was received.
+	pub body: String,
+}
+
+impl Failure {
+	pub fn new(status: Option<u16>, body: &str) -> Self {
+		Self {
+			at: now(),
+			status,
+			body: body.chars().take(SNIPPET_LEN).collect(),
+		}
+	}
+}
+
+/// A delivery log with the subscription as of its latest delivery, so a dead letter still says
+/// what was removed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogEntry {
+	pub subscription: Subscription,
+	pub log: DeliveryLog,
+}
+
+impl Key for LogKey {
+	type Value = LogEntry;
+
+	type Error = Error;
+
+	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
+		decode(bytes)
+	}
+
+	fn to_bytes(&self) -> Result<IVec, Self::Error> {
+		encode(self)
+	}
+}
+
+impl Value for LogEntry {
+	type Error = Error;
+
+	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
+		decode(bytes)
+	}
+
+	fn to_bytes(&self) -> Result<IVec, Self::Error> {
+		encode(self)
+	}
+}
+
+/// Apply `f` to the subscription's log, returning the updated log.
+fn update<F>(tree: &Tree, id: SubscriptionId, sub: &Subscription, f: F) -> Result<DeliveryLog>
+where
+	F: Fn(&mut DeliveryLog),
+{
+	let key = LogKey(id);
+
+	tree.transaction::<_, _, Error>(|txn| {
+		let mut log = key
+			.get(txn)
+			.unwrap()
+			.map(|entry| entry.log)
+			.unwrap_or_default();
+		f(&mut log);
+
+		let entry = LogEntry {
+			subscription: sub.clone(),
+			log: log.clone(),
+		};
+		key.insert(txn, entry).unwrap();
+
+		Ok(log)
+	})
+	.map_err(|e| anyhow!("{}", e))
+}
+
+pub fn record_success(tree: &Tree, id: SubscriptionId, sub: &Subscription) -> Result<()> {
+	update(tree, id, sub, |log| {
+		log.total_deliveries += 1;
+		log.consecutive_failures = 0;
+		log.last_success = Some(now());
+		log.removed_at = None;
+	})?;
+	Ok(())
+}
+
+/// Record a failed delivery. Returns whether it was the `max_failures`th in a row, after which the
+/// subscription is to be removed.
+pub fn record_failure(
+	tree: &Tree,
+	id: SubscriptionId,
+	sub: &Subscription,
+	failure: Failure,
+	max_failures: u64,
+) -> Result<bool> {
+	let log = update(tree, id, sub, |log| {
+		log.total_failures += 1;
+		log.consecutive_failures += 1;
+		if log.consecutive_failures >= max_failures {
+			log.removed_at = Some(failure.at);
+		}
+		log.last_failure = Some(failure.clone());
+	})?;
+
+	Ok(log.removed_at.is_some())
+}
+
+/// Every log matching the predicate, with the ID of its subscription.
+pub fn find<F>(tree: &Tree, f: F) -> Result<Vec<(SubscriptionId, LogEntry)>>
+where
+	F: Fn(&LogEntry) -> bool,
+{
+	let mut entries = vec![];
+
+	for entry in tree.iter() {
+		let (key, value) = entry?;
+		let LogKey(id) = LogKey::from_bytes(&key)?;
+		let entry = LogEntry::from_bytes(&value)?;
+
+		if f(&entry) {
+			entries.push((id, entry));
+		}
+	}
+
+	Ok(entries)
+}
+
+/// Remove the logs of subscriptions that are no longer registered once nothing has happened to
+/// them for `days`. Logs of registered subscriptions are kept however old they are.
+fn prune(tree: &Tree, index: &Index, days: u64) -> Result<usize> {
+	let oldest = now().saturating_sub(days * DAY);
+
+	let mut batch = Batch::default();
+	let mut removed = 0;
+	for entry in tree.iter() {
+		let (key, value) = entry?;
+		let LogKey(id) = LogKey::from_bytes(&key)?;
+
+		if index.subscription(id).is_none()
+			&& LogEntry::from_bytes(&value)?.log.last_event() < oldest
+		{
+			batch.remove(key);
+			removed += 1;
+		}
+	}
+
+	tree.apply_batch(batch)?;
+	Ok(removed)
+}
+
+/// Periodically remove expired delivery logs until shutdown.
+pub async fn run(state: State) {
+	let mut interval = interval(Duration::from_secs(60 * 60));
+	let mut shutdown = state.shutdown.clone();
+
+	loop {
+		select! {
+			_ = interval.tick() => {}
+			_ = stopped(&mut shutdown) => break,
+		}
+
+		let days = state.config.retention.delivery_logs;
+		match prune(&state.delivery_log, &state.index, days) {
+			Ok(0) => {}
+			Ok(removed) => info!("Removed {} expired delivery logs", removed),
+			Err(e) => warn!("Error removing expired delivery logs: {}", e),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{prune, record_failure, record_success, Failure, LogEntry, LogKey, DAY};
+	use crate::{
+		index::Index,
+		model::{Filter, Filters, Format, Subscription, SubscriptionId},
+		util::now,
+	};
+	use sled_ext::key::Key;
+
+	fn subscription(webhook_url: &str) -> Subscription {
+		Subscription {
+			webhook_url: webhook_url.into(),
+			format: Format::Raw,
+			digest: None,
+			paused: false,
+			expires_at: None,
+			friendly: Default::default(),
+			battles: false,
+		}
+	}
+
+	#[test]
+	fn prunes_old_logs_of_removed_subscriptions() {
+		let db = sled::Config::new().temporary(true).open().unwrap();
+		let tree = db.open_tree("delivery_log").unwrap();
+		let index = Index::load(&db, "webhooks").unwrap();
+
+		let registered = subscription("https://example.com/registered");
+		let mut filters = Filters::new();
+		filters.insert(Filter::All, Some(registered.clone()).into_iter().collect());
+		let (id, _) = index.subscribe(&filters).unwrap().remove(0);
+
+		let removed = subscription("https://example.com/removed");
+		let recent = subscription("https://example.com/recent");
+
+		let long_ago = Failure {
+			at: now() - 31 * DAY,
+			status: Some(404),
+			body: String::new(),
+		};
+		record_failure(&tree, id, &registered, long_ago.clone(), 1).unwrap();
+		record_failure(&tree, SubscriptionId(100), &removed, long_ago, 1).unwrap();
+		record_success(&tree, SubscriptionId(101), &recent).unwrap();
+		record_failure(
+			&tree,
+			SubscriptionId(101),
+			&recent,
+			Failure::new(Some(404), ""),
+			1,
+		)
+		.unwrap();
+
+		assert_eq!(prune(&tree, &index, 30).unwrap(), 1);
+
+		let get = |id| -> Option<LogEntry> { LogKey(id).get(&tree).unwrap() };
+		assert!(get(id).is_some());
+		assert!(get(SubscriptionId(100)).is_none());
+		assert_eq!(get(SubscriptionId(101)).unwrap().log.total_deliveries, 1);
+	}
+
+	#[test]
+	fn removes_after_consecutive_failures() {
+		let db = sled::Config::new().temporary(true).open().unwrap();
+		let tree = db.open_tree("delivery_log").unwrap();
+		let sub = subscription("https://example.com/flaky");
+		let id = SubscriptionId(1);
+		let fail = || record_failure(&tree, id, &sub, Failure::new(Some(502), ""), 3).unwrap();
+
+		assert!(!fail());
+		assert!(!fail());
+		record_success(&tree, id, &sub).unwrap();
+		assert!(!fail());
+		assert!(!fail());
+		assert!(fail());
+
+		let log = LogKey(id).get(&tree).unwrap().unwrap().log;
+		assert_eq!(log.total_failures, 5);
+		assert_eq!(log.consecutive_failures, 3);
+		assert!(log.removed_at.is_some());
+	}
+}
@@ -0,0 +1,377 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{anyhow, Error, Result};
+use serde::{Deserialize, Serialize};
+use sled::IVec;
+use sled_ext::{key::Key, value::Value};
+use tokio::{select, time::interval};
+use tracing::log::warn;
+
+use crate::{
+	delivery::post,
+	format::Payload,
+	model::{zkb::Killmail, Outcome, SubscriptionId},
+	storage::{decode, encode},
+	util::{format_isk, now, stopped},
+	State,
+};
+
+/// How many entries to list in the top kills and top ships sections of a summary.
+const TOP: usize = 5;
+
+/// Key of a subscription's pending digest.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DigestKey(pub SubscriptionId);
+
+/// Kills collected for a digest subscription since its last summary.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Digest {
+	pub started_at: u64,
+	pub count: usize,
+	pub total_value: f64,
+	/// Number of kills by victim ship type.
+	pub ships: HashMap<usize, usize>,
+	/// The most valuable kills, most valuable first, up to the retention limit.
+	pub kills: Vec<DigestKill>,
+	pub outcomes: Outcomes,
+}
+
+/// Kills counted by what they were from the subscription's side. Kills it had no side in aren't
+/// counted.
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Outcomes {
+	pub kills: usize,
+	pub losses: usize,
+	pub awoxes: usize,
+}
+
+impl Outcomes {
+	fn add(&mut self, outcome: Outcome) {
+		match outcome {
+			Outcome::Kill => self.kills += 1,
+			Outcome::Loss => self.losses += 1,
+			Outcome::Awox => self.awoxes += 1,
+		}
+	}
+
+	fn is_empty(&self) -> bool {
+		self.kills == 0 && self.losses == 0 && self.awoxes == 0
+	}
+}
+
+impl Digest {
+	fn push(&mut self, kill: DigestKill, outcome: Option<Outcome>, limit: usize) {
+		self.count += 1;
+		self.total_value += kill.value;
+		*self.ships.entry(kill.ship_type_id).or_default() += 1;
+		if let Some(outcome) = outcome {
+			self.outcomes.add(outcome);
+		}
+
+		let index = self
+			.kills
+			.iter()
+			.position(|existing| existing.value < kill.value)
+			.unwrap_or_else(|| self.kills.len());
+		self.kills.insert(index, kill);
+		self.kills.truncate(limit);
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DigestKill {
+	pub killmail_id: usize,
+	pub ship_type_id: usize,
+	pub value: f64,
+	pub url: String,
+}
+
+impl From<&Killmail> for DigestKill {
+	fn from(km: &Killmail) -> Self {
+		Self {
+			killmail_id: km.killmail_id,
+			ship_type_id: km.victim.ship_type_id,
+			value: km.value().unwrap_or_default(),
+			url: km.zkb.url.clone(),
+		}
+	}
+}
+
+impl Key for DigestKey {
+	type Value = Digest;
+
+	type Error = Error;
+
+	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
+		decode(bytes)
+	}
+
+	fn to_bytes(&self) -> Result<IVec, Self::Error> {
+		encode(self)
+	}
+}
+
+impl Value for Digest {
+	type Error = Error;
+
+	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
+		decode(bytes)
+	}
+
+	fn to_bytes(&self) -> Result<IVec, Self::Error> {
+		encode(self)
+	}
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShipCount {
+	pub ship_type_id: usize,
+	pub count: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Summary {
+	pub started_at: u64,
+	pub ended_at: u64,
+	pub kills: usize,
+	pub total_value: f64,
+	pub outcomes: Outcomes,
+	pub top_kills: Vec<DigestKill>,
+	pub top_ships: Vec<ShipCount>,
+}
+
+impl Summary {
+	fn new(digest: Digest) -> Self {
+		let mut top_ships = digest
+			.ships
+			.into_iter()
+			.map(|(ship_type_id, count)| ShipCount {
+				ship_type_id,
+				count,
+			})
+			.collect::<Vec<_>>();
+		top_ships.sort_by(|a, b| b.count.cmp(&a.count));
+		top_ships.truncate(TOP);
+
+		let mut top_kills = digest.kills;
+		top_kills.truncate(TOP);
+
+		Self {
+			started_at: digest.started_at,
+			ended_at: now(),
+			kills: digest.count,
+			total_value: digest.total_value,
+			outcomes: digest.outcomes,
+			top_kills,
+			top_ships,
+		}
+	}
+
+	pub fn text(&self) -> String {
+		let mut text = format!(
+			"**Kill digest**: {} kills, {} ISK destroyed",
+			self.kills,
+			format_isk(self.total_value)
+		);
+
+		if !self.outcomes.is_empty() {
+			text.push_str(&format!(
+				"\n{} kills, {} losses, {} awoxes",
+				self.outcomes.kills, self.outcomes.losses, self.outcomes.awoxes
+			));
+		}
+
+		text.push_str("\n\n**Top kills**");
+		for (i, kill) in self.top_kills.iter().enumerate() {
+			text.push_str(&format!(
+				"\n{}. {} ISK - {}",
+				i + 1,
+				format_isk(kill.value),
+				kill.url
+			));
+		}
+
+		text.push_str("\n\n**Top ships**");
+		for (i, ship) in self.top_ships.iter().enumerate() {
+			text.push_str(&format!(
+				"\n{}. <https://zkillboard.com/ship/{}/> x{}",
+				i + 1,
+				ship.ship_type_id,
+				ship.count
+			));
+		}
+
+		text
+	}
+}
+
+/// Add a killmail to the pending digest for the subscription, along with what it was from the
+/// subscription's side.
+pub fn record(
+	state: &State,
+	id: SubscriptionId,
+	km: &Killmail,
+	outcome: Option<Outcome>,
+) -> Result<()> {
+	let kill = DigestKill::from(km);
+	let limit = state.config.retention.digest_kills;
+	let key = DigestKey(id);
+
+	state
+		.digests
+		.transaction::<_, _, Error>(|txn| {
+			let mut digest = key.get(txn).unwrap().unwrap_or_else(|| Digest {
+				started_at: now(),
+				..Digest::default()
+			});
+
+			digest.push(kill.clone(), outcome, limit);
+			key.insert(txn, digest).unwrap();
+
+			Ok(())
+		})
+		.map_err(|e| anyhow!("{}", e))
+}
+
+/// Post summaries for every digest whose interval has elapsed. Digests of paused subscriptions
+/// are held until they resume, and those of removed subscriptions are dropped.
+async fn flush(state: &State) -> Result<()> {
+	for entry in state.digests.iter() {
+		let (key, value) = entry?;
+		let key = DigestKey::from_bytes(&key)?;
+
+		let sub = match state.index.subscription(key.0) {
+			Some(sub) => sub,
+			None => {
+				key.remove(&state.digests)?;
+				continue;
+			}
+		};
+
+		let started_at = Digest::from_bytes(&value)?.started_at;
+		let period = sub.digest.unwrap_or_default() * 60;
+
+		if sub.paused || started_at + period > now() {
+			continue;
+		}
+
+		// take the latest value so kills recorded since iterating are included
+		let digest = match key.remove(&state.digests)? {
+			Some(digest) => digest,
+			None => continue,
+		};
+
+		let summary = Summary::new(digest);
+		if post(state, key.0, sub, Payload::Digest(&summary))
+			.await?
+			.is_some()
+		{
+			state.index.remove(key.0)?;
+		}
+	}
+
+	Ok(())
+}
+
+/// Periodically post digest summaries until shutdown.
+pub async fn run(state: State) {
+	let mut interval = interval(Duration::from_secs(60));
+	let mut shutdown = state.shutdown.clone();
+
+	loop {
+		select! {
+			_ = interval.tick() => {}
+			_ = stopped(&mut shutdown) => break,
+		}
+
+		if let Err(e) = flush(&state).await {
+			warn!("Error flushing digests: {}", e);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Digest, DigestKill, Outcomes, Summary};
+	use crate::model::Outcome;
+
+	fn kill(killmail_id: usize, ship_type_id: usize, value: f64) -> DigestKill {
+		DigestKill {
+			killmail_id,
+			ship_type_id,
+			value,
+			url: format!("https://zkillboard.com/kill/{}/", killmail_id),
+		}
+	}
+
+	#[test]
+	fn push_keeps_most_valuable_kills() {
+		let mut digest = Digest::default();
+		for (id, ship, value) in &[
+			(1, 587, 5.0),
+			(2, 587, 20.0),
+			(3, 11_567, 1.0),
+			(4, 587, 10.0),
+		] {
+			digest.push(kill(*id, *ship, *value), None, 3);
+		}
+
+		let kills = digest
+			.kills
+			.iter()
+			.map(|kill| kill.killmail_id)
+			.collect::<Vec<_>>();
+		assert_eq!(kills, vec![2, 4, 1]);
+
+		// everything still counts towards the totals
+		assert_eq!(digest.count, 4);
+		assert_eq!(digest.total_value, 36.0);
+		assert_eq!(digest.ships.get(&587), Some(&3));
+		assert_eq!(digest.ships.get(&11_567), Some(&1));
+	}
+
+	#[test]
+	fn push_keeps_earlier_of_equal_kills() {
+		let mut digest = Digest::default();
+		digest.push(kill(1, 587, 10.0), None, 1);
+		digest.push(kill(2, 587, 10.0), None, 1);
+
+		assert_eq!(digest.kills.len(), 1);
+		assert_eq!(digest.kills[0].killmail_id, 1);
+	}
+
+	#[test]
+	fn counts_outcomes() {
+		let mut digest = Digest::default();
+		for (id, outcome) in &[
+			(1, Some(Outcome::Kill)),
+			(2, Some(Outcome::Kill)),
+			(3, Some(Outcome::Loss)),
+			(4, Some(Outcome::Awox)),
+			(5, None),
+		] {
+			digest.push(kill(*id, 587, 1.0), *outcome, 5);
+		}
+
+		assert_eq!(digest.count, 5);
+		assert_eq!(
+			digest.outcomes,
+			Outcomes {
+				kills: 2,
+				losses: 1,
+				awoxes: 1,
+			}
+		);
+
+		let text = Summary::new(digest).text();
+		assert!(text.contains("\n2 kills, 1 losses, 1 awoxes\n"));
+	}
+
+	#[test]
+	fn omits_outcomes_without_a_side() {
+		let mut digest = Digest::default();
+		digest.push(kill(1, 587, 1.0), None, 5);
+
+		assert!(!Summary::new(digest).text().contains("awoxes"));
+	}
+}
@@ -0,0 +1,417 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, Error, Result};
+use futures::{future::BoxFuture, FutureExt};
+use reqwest::{header::CONTENT_TYPE, Client, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{from_slice, to_vec};
+use sled::{IVec, Tree};
+use sled_ext::{key::Key, value::Value};
+
+use crate::{
+	model::zkb::{Attacker, Killmail, PartialKillmail, Victim},
+	storage::{decode, encode},
+	util::now,
+};
+
+/// Most IDs `/universe/names/` accepts in one request.
+const NAMES_CHUNK: usize = 1000;
+
+/// Client for EVE's ESI API.
+#[derive(Debug)]
+pub struct Esi {
+	pub client: Client,
+	/// Base URL, e.g. `https://esi.evetech.net/latest`.
+	pub url: String,
+	/// Resolved names, keyed by ID.
+	pub names: Tree,
+	/// Seconds a resolved name is used before it's resolved again.
+	pub name_ttl: u64,
+	/// Killmails fetched by ID and hash, which never change.
+	pub killmails: Tree,
+	/// Item types and their groups, which only change with the game's static data.
+	pub types: Tree,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NameKey(pub usize);
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Name {
+	pub id: usize,
+	pub name: String,
+	pub category: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CachedName {
+	pub name: Name,
+	pub fetched_at: u64,
+}
+
+impl Key for NameKey {
+	type Value = CachedName;
+
+	type Error = Error;
+
+	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
+		decode(bytes)
+	}
+
+	fn to_bytes(&self) -> Result<IVec, Self::Error> {
+		encode(self)
+	}
+}
+
+impl Value for CachedName {
+	type Error = Error;
+
+	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
+		decode(bytes)
+	}
+
+	fn to_bytes(&self) -> Result<IVec, Self::Error> {
+		encode(self)
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KillmailKey(pub usize);
+
+/// A killmail as ESI returns it, without zKillboard's additions.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EsiKillmail {
+	pub attackers: Vec<Attacker>,
+	pub killmail_id: usize,
+	pub killmail_time: String,
+	pub solar_system_id: usize,
+	pub victim: Victim,
+}
+
+impl Key for KillmailKey {
+	type Value = EsiKillmail;
+
+	type Error = Error;
+
+	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
+		decode(bytes)
+	}
+
+	fn to_bytes(&self) -> Result<IVec, Self::Error> {
+		encode(self)
+	}
+}
+
+impl Value for EsiKillmail {
+	type Error = Error;
+
+	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
+		decode(bytes)
+	}
+
+	fn to_bytes(&self) -> Result<IVec, Self::Error> {
+		encode(self)
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TypeKey(pub usize);
+
+/// An item type, such as a ship, and the group it belongs to, such as its ship class.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ItemType {
+	pub name: String,
+	pub group_id: usize,
+	/// Name of the group.
+	#[serde(default)]
+	pub group: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Group {
+	name: String,
+}
+
+impl Key for TypeKey {
+	type Value = ItemType;
+
+	type Error = Error;
+
+	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
+		decode(bytes)
+	}
+
+	fn to_bytes(&self) -> Result<IVec, Self::Error> {
+		encode(self)
+	}
+}
+
+impl Value for ItemType {
+	type Error = Error;
+
+	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
+		decode(bytes)
+	}
+
+	fn to_bytes(&self) -> Result<IVec, Self::Error> {
+		encode(self)
+	}
+}
+
+impl Esi {
+	fn url(&self, path: &str) -> String {
+		format!("{}{}", self.url.trim_end_matches('/'), path)
+	}
+
+	/// Resolve the names of the IDs, from the cache where possible.
+	pub async fn names(&self, ids: HashSet<usize>) -> Result<HashMap<usize, String>> {
+		let now = now();
+		let mut names = HashMap::with_capacity(ids.len());
+		let mut missing = vec![];
+
+		for id in ids {
+			match NameKey(id).get(&self.names)? {
+				Some(cached) if cached.fetched_at + self.name_ttl > now => {
+					names.insert(id, cached.name.name);
+				}
+				_ => missing.push(id),
+			}
+		}
+
+		for chunk in missing.chunks(NAMES_CHUNK) {
+			for name in self.resolve(chunk).await? {
+				names.insert(name.id, name.name.clone());
+				NameKey(name.id).insert(
+					&self.names,
+					CachedName {
+						name,
+						fetched_at: now,
+					},
+				)?;
+			}
+		}
+
+		Ok(names)
+	}
+
+	/// Look up the names of the IDs. ESI rejects the whole request when any of them is invalid, so a
+	/// rejected request is split in half until the invalid IDs are left on their own and skipped.
+	fn resolve<'a>(&'a self, ids: &'a [usize]) -> BoxFuture<'a, Result<Vec<Name>>> {
+		async move {
+			let response = self
+				.client
+				.post(self.url("/universe/names/"))
+				.header(CONTENT_TYPE, "application/json")
+				.body(to_vec(ids)?)
+				.send()
+				.await?;
+
+			if response.status() != StatusCode::NOT_FOUND {
+				return Ok(from_slice(&response.error_for_status()?.bytes().await?)?);
+			}
+
+			if ids.len() == 1 {
+				return Ok(vec![]);
+			}
+
+			let (left, right) = ids.split_at(ids.len() / 2);
+			let mut names = self.resolve(left).await?;
+			names.extend(self.resolve(right).await?);
+			Ok(names)
+		}
+		.boxed()
+	}
+
+	/// Fetch the rest of a partial killmail, from the cache if it was fetched before.
+	pub async fn killmail(&self, partial: PartialKillmail) -> Result<Killmail> {
+		let key = KillmailKey(partial.killmail_id);
+
+		let esi = match key.get(&self.killmails)? {
+			Some(esi) => esi,
+			None => {
+				let hash = partial
+					.hash()
+					.ok_or_else(|| anyhow!("Killmail {} has no hash", partial.killmail_id))?;
+
+				let esi = self
+					.get::<EsiKillmail>(&format!("/killmails/{}/{}/", partial.killmail_id, hash))
+					.await?;
+				key.insert(&self.killmails, esi.clone())?;
+				esi
+			}
+		};
+
+		let mut zkb = partial.zkb;
+		if zkb.url.is_empty() {
+			zkb.url = format!("https://zkillboard.com/kill/{}/", esi.killmail_id);
+		}
+
+		Ok(Killmail {
+			attackers: esi.attackers,
+			killmail_id: esi.killmail_id,
+			killmail_time: esi.killmail_time,
+			solar_system_id: esi.solar_system_id,
+			victim: esi.victim,
+			zkb,
+			names: HashMap::new(),
+			percentile: None,
+		})
+	}
+
+	async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+		let response = self
+			.client
+			.get(self.url(path))
+			.send()
+			.await?
+			.error_for_status()?;
+
+		Ok(from_slice(&response.bytes().await?)?)
+	}
+
+	/// An item type and its group, if it was looked up before.
+	pub fn cached_item_type(&self, type_id: usize) -> Result<Option<ItemType>> {
+		TypeKey(type_id).get(&self.types)
+	}
+
+	/// Look up an item type and its group, from the cache if it was looked up before.
+	pub async fn item_type(&self, type_id: usize) -> Result<ItemType> {
+		if let Some(item_type) = self.cached_item_type(type_id)? {
+			return Ok(item_type);
+		}
+
+		let mut item_type = self
+			.get::<ItemType>(&format!("/universe/types/{}/", type_id))
+			.await?;
+		item_type.group = self
+			.get::<Group>(&format!("/universe/groups/{}/", item_type.group_id))
+			.await?
+			.name;
+
+		TypeKey(type_id).insert(&self.types, item_type.clone())?;
+		Ok(item_type)
+	}
+
+	/// Attach the names of everything involved in the killmail.
+	pub async fn enrich(&self, km: &mut Killmail) -> Result<()> {
+		km.names = self.names(km.ids()).await?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{collections::HashMap, convert::Infallible};
+
+	use hyper::{
+		body::to_bytes,
+		service::{make_service_fn, service_fn},
+		Body, Request, Response, Server, StatusCode,
+	};
+	use reqwest::Client;
+	use serde_json::{from_slice, from_str, json, Value};
+	use sled_ext::key::Key;
+
+	use super::{Esi, KillmailKey, NameKey};
+	use crate::model::zkb::{PartialKillmail, EXAMPLE};
+
+	/// An ID that ESI rejects.
+	const INVALID: usize = 0;
+
+	async fn respond(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+		let path = req.uri().path().to_owned();
+		let body = to_bytes(req.into_body()).await.unwrap();
+
+		let response = match path.as_str() {
+			"/universe/names/" => {
+				let ids = from_slice::<Vec<usize>>(&body).unwrap();
+				if ids.contains(&INVALID) {
+					Response::builder()
+						.status(StatusCode::NOT_FOUND)
+						.body(Body::from(
+							r#"{"error":"Ensure all IDs are valid before resolving"}"#,
+						))
+				} else {
+					let names = ids
+						.iter()
+						.map(
+							|id| json!({ "id": id, "name": format!("Name {}", id), "category": "character" }),
+						)
+						.collect::<Vec<_>>();
+					Response::builder().body(Body::from(json!(names).to_string()))
+				}
+			}
+			"/killmails/96215665/cff36d79e4b17b6eca051a08b38a1b22170670dd/" => {
+				let mut km = from_str::<Value>(EXAMPLE).unwrap();
+				km.as_object_mut().unwrap().remove("zkb");
+				Response::builder().body(Body::from(km.to_string()))
+			}
+			_ => Response::builder()
+				.status(StatusCode::NOT_FOUND)
+				.body(Body::empty()),
+		};
+
+		Ok(response.unwrap())
+	}
+
+	/// Serve a mock of the parts of ESI that are used on a local port.
+	fn esi() -> Esi {
+		let make = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(respond)) });
+		let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make);
+		let url = format!("http://{}", server.local_addr());
+		tokio::spawn(server);
+
+		let db = sled::Config::new().temporary(true).open().unwrap();
+		Esi {
+			client: Client::new(),
+			url,
+			names: db.open_tree("names").unwrap(),
+			name_ttl: 60,
+			killmails: db.open_tree("killmails").unwrap(),
+			types: db.open_tree("types").unwrap(),
+		}
+	}
+
+	#[tokio::test]
+	async fn skips_invalid_ids() {
+		let esi = esi();
+
+		let names = esi
+			.names([1, 2, INVALID, 3].iter().copied().collect())
+			.await
+			.unwrap();
+
+		let expected = [1, 2, 3]
+			.iter()
+			.map(|id| (*id, format!("Name {}", id)))
+			.collect::<HashMap<_, _>>();
+		assert_eq!(names, expected);
+		assert!(NameKey(2).get(&esi.names).unwrap().is_some());
+		assert!(NameKey(INVALID).get(&esi.names).unwrap().is_none());
+	}
+
+	#[tokio::test]
+	async fn fetches_partial_killmails() {
+		let esi = esi();
+		let partial = from_str::<PartialKillmail>(
+			r#"{"killID":96215665,"zkb":{"hash":"cff36d79e4b17b6eca051a08b38a1b22170670dd"}}"#,
+		)
+		.unwrap();
+
+		let km = esi.killmail(partial).await.unwrap();
+		assert_eq!(km.killmail_id, 96215665);
+		assert_eq!(km.victim.ship_type_id, 602);
+		assert_eq!(km.attackers.len(), 1);
+		assert_eq!(km.zkb.url, "https://zkillboard.com/kill/96215665/");
+		// zKillboard hadn't priced it
+		assert_eq!(km.value(), None);
+		assert!(KillmailKey(96215665).get(&esi.killmails).unwrap().is_some());
+
+		// the cached copy is used without a hash
+		let partial = from_str::<PartialKillmail>(r#"{"killID":96215665}"#).unwrap();
+		assert_eq!(
+			esi.killmail(partial).await.unwrap().victim.ship_type_id,
+			602
+		);
+	}
+}
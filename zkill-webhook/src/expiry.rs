@@ -0,0 +1,32 @@
+use std::time::Duration;
+
+use tokio::{select, time::interval};
+use tracing::log::{info, warn};
+
+use crate::{
+	util::{now, stopped},
+	State,
+};
+
+/// Periodically remove expired subscriptions until shutdown.
+pub async fn run(state: State) {
+	let mut interval = interval(Duration::from_secs(60));
+	let mut shutdown = state.shutdown.clone();
+
+	loop {
+		select! {
+			_ = interval.tick() => {}
+			_ = stopped(&mut shutdown) => break,
+		}
+
+		let now = now();
+		match state
+			.index
+			.update(|sub| Some(sub.clone()).filter(|sub| !sub.is_expired(now)))
+		{
+			Ok(0) => {}
+			Ok(removed) => info!("Removed {} expired subscriptions", removed),
+			Err(e) => warn!("Error removing expired subscriptions: {}", e),
+		}
+	}
+}
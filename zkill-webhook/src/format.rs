@@ -0,0 +1,272 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::Result;
+use reqwest::{header::CONTENT_TYPE, Client, RequestBuilder};
+use serde_json::{json, to_value, to_vec, Value};
+
+use crate::{
+	battle::Report,
+	digest::Summary,
+	model::{zkb::Killmail, Format, Outcome},
+	template::TemplateOutput,
+	util::now,
+};
+
+/// Counter making Matrix transaction IDs unique within a second.
+static TXN_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Something to deliver to a subscription, before it's been shaped for the destination.
+#[derive(Debug, Clone, Copy)]
+pub enum Payload<'a> {
+	/// A killmail and what it was from the subscription's side.
+	Killmail(&'a Killmail, Option<Outcome>),
+	Digest(&'a Summary),
+	Battle(&'a Report),
+}
+
+impl Payload<'_> {
+	fn text(&self) -> String {
+		match self {
+			Self::Killmail(km, Some(outcome)) => format!("**{}** {}", outcome.label(), km.text()),
+			Self::Killmail(km, None) => km.text(),
+			Self::Digest(summary) => summary.text(),
+			Self::Battle(report) => report.text(),
+		}
+	}
+
+	fn raw(&self) -> Result<Vec<u8>> {
+		Ok(match self {
+			Self::Killmail(km, outcome) => {
+				let mut value = to_value(km)?;
+				if let (Value::Object(map), Some(outcome)) = (&mut value, outcome) {
+					map.insert("outcome".into(), to_value(outcome)?);
+				}
+				to_vec(&value)?
+			}
+			Self::Digest(summary) => to_vec(summary)?,
+			Self::Battle(report) => to_vec(report)?,
+		})
+	}
+
+	/// A Discord message, with the outcome as a coloured embed when there is one.
+	fn discord(&self) -> Result<Vec<u8>> {
+		let body = match self {
+			Self::Killmail(km, Some(outcome)) => json!({
+				"embeds": [{
+					"title": outcome.label(),
+					"description": km.text(),
+					"url": km.zkb.url,
+					"color": outcome.color(),
+				}]
+			}),
+			_ => json!({ "content": self.text() }),
+		};
+
+		Ok(to_vec(&body)?)
+	}
+}
+
+/// The text's `**bold**` markdown as HTML, for clients that don't render markdown.
+fn html(text: &str) -> String {
+	let escaped = text
+		.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;");
+
+	let mut html = String::with_capacity(escaped.len());
+	for (i, part) in escaped.split("**").enumerate() {
+		match i {
+			0 => {}
+			i if i % 2 == 1 => html.push_str("<strong>"),
+			_ => html.push_str("</strong>"),
+		}
+		html.push_str(part);
+	}
+
+	html.replace('\n', "<br>")
+}
+
+fn with_json(builder: RequestBuilder, body: Vec<u8>) -> RequestBuilder {
+	builder.header(CONTENT_TYPE, "application/json").body(body)
+}
+
+impl Format {
+	/// Build the request delivering the payload to `url` in this format.
+	pub fn request(
+		&self,
+		client: &Client,
+		url: &str,
+		payload: Payload<'_>,
+	) -> Result<RequestBuilder> {
+		let text = || payload.text();
+
+		Ok(match self {
+			Self::Raw => with_json(client.post(url), payload.raw()?),
+			Self::Discord => with_json(client.post(url), payload.discord()?),
+			Self::Template(template) => {
+				let body = match (payload, template.output) {
+					(Payload::Killmail(km, outcome), _) => template.render(km, outcome)?,
+					(_, TemplateOutput::Discord) => to_vec(&json!({ "content": text() }))?,
+					(_, TemplateOutput::Raw) => payload.raw()?,
+				};
+
+				with_json(client.post(url), body)
+			}
+			// Slack's mrkdwn uses single asterisks for bold
+			Self::Slack => with_json(
+				client.post(url),
+				to_vec(&json!({ "text": text().replace("**", "*") }))?,
+			),
+			Self::Mattermost => with_json(client.post(url), to_vec(&json!({ "text": text() }))?),
+			Self::Matrix { access_token } => {
+				let txn_id = format!("{}.{}", now(), TXN_ID.fetch_add(1, Ordering::Relaxed));
+				let text = text();
+				let body = json!({
+					"msgtype": "m.text",
+					"body": text.replace("**", ""),
+					"format": "org.matrix.custom.html",
+					"formatted_body": html(&text),
+				});
+
+				with_json(
+					client
+						.put(format!("{}/{}", url.trim_end_matches('/'), txn_id))
+						.bearer_auth(access_token),
+					to_vec(&body)?,
+				)
+			}
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use reqwest::{header::AUTHORIZATION, Client, Method, Request};
+	use serde_json::{from_slice, from_str, json, Value};
+
+	use super::{html, Payload};
+	use crate::{
+		model::{
+			zkb::{Killmail, EXAMPLE},
+			Format, Outcome,
+		},
+		template::{Template, TemplateOutput},
+	};
+
+	const URL: &str = "https://example.com/hook";
+
+	fn killmail() -> Killmail {
+		let mut km = from_str::<Killmail>(EXAMPLE).unwrap();
+		km.names.insert(2119260464, "Victim".into());
+		km.names.insert(602, "Kestrel".into());
+		km.names.insert(30004979, "Tama".into());
+		km
+	}
+
+	fn request(format: Format, km: &Killmail, outcome: Option<Outcome>) -> Request {
+		format
+			.request(&Client::new(), URL, Payload::Killmail(km, outcome))
+			.unwrap()
+			.build()
+			.unwrap()
+	}
+
+	fn body(request: &Request) -> Value {
+		from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap()
+	}
+
+	fn is_post_json(request: &Request) -> bool {
+		request.method() == Method::POST
+			&& request.url().as_str() == URL
+			&& request.headers()["content-type"] == "application/json"
+	}
+
+	#[test]
+	fn builds_raw_requests() {
+		let km = killmail();
+		let request = request(Format::Raw, &km, Some(Outcome::Loss));
+
+		assert!(is_post_json(&request));
+		let body = body(&request);
+		assert_eq!(body["killmail_id"], json!(96215665));
+		assert_eq!(body["outcome"], json!("loss"));
+	}
+
+	#[test]
+	fn builds_discord_requests() {
+		let km = killmail();
+
+		let request = request(Format::Discord, &km, None);
+		assert!(is_post_json(&request));
+		assert_eq!(body(&request), json!({ "content": km.text() }));
+
+		let embed = &body(&self::request(Format::Discord, &km, Some(Outcome::Kill)))["embeds"][0];
+		assert_eq!(embed["title"], json!("KILL"));
+		assert_eq!(embed["description"], json!(km.text()));
+		assert_eq!(embed["url"], json!(km.zkb.url));
+	}
+
+	#[test]
+	fn builds_template_requests() {
+		let template = Template {
+			source: "{victim.ship} died".into(),
+			output: TemplateOutput::Discord,
+		};
+
+		let request = request(Format::Template(template), &killmail(), None);
+		assert!(is_post_json(&request));
+		assert_eq!(body(&request), json!({ "content": "Kestrel died" }));
+	}
+
+	#[test]
+	fn builds_slack_and_mattermost_requests() {
+		let km = killmail();
+
+		let slack = request(Format::Slack, &km, Some(Outcome::Kill));
+		assert!(is_post_json(&slack));
+		let text = body(&slack)["text"].as_str().unwrap().to_owned();
+		assert!(text.starts_with("*KILL* Victim lost a *Kestrel* in Tama"));
+		assert!(!text.contains("**"));
+
+		let mattermost = request(Format::Mattermost, &km, Some(Outcome::Kill));
+		assert!(is_post_json(&mattermost));
+		assert_eq!(
+			body(&mattermost)["text"],
+			json!(format!("**KILL** {}", km.text()))
+		);
+	}
+
+	#[test]
+	fn builds_matrix_requests() {
+		let format = Format::Matrix {
+			access_token: "secret".into(),
+		};
+		let request = request(format, &killmail(), Some(Outcome::Kill));
+
+		assert_eq!(request.method(), Method::PUT);
+		assert!(request
+			.url()
+			.as_str()
+			.starts_with("https://example.com/hook/"));
+		assert_eq!(request.headers()[AUTHORIZATION], "Bearer secret");
+
+		let body = body(&request);
+		assert_eq!(body["msgtype"], json!("m.text"));
+		assert_eq!(body["format"], json!("org.matrix.custom.html"));
+		let plain = body["body"].as_str().unwrap();
+		assert!(plain.starts_with("KILL Victim lost a Kestrel in Tama"));
+		let formatted = body["formatted_body"].as_str().unwrap();
+		assert!(
+			formatted.starts_with("<strong>KILL</strong> Victim lost a <strong>Kestrel</strong>")
+		);
+		assert!(formatted.contains("<br>https://zkillboard.com/kill/96215665/"));
+	}
+
+	#[test]
+	fn converts_bold_to_html() {
+		assert_eq!(
+			html("**Top ships**\n1. <https://zkillboard.com/ship/602/> x2 & more"),
+			"<strong>Top ships</strong><br>1. &lt;https://zkillboard.com/ship/602/&gt; x2 &amp; more"
+		);
+	}
+}
@@ -0,0 +1,669 @@
+use std::{
+	collections::{HashMap, HashSet},
+	hash::Hash,
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc, Mutex, RwLock,
+	},
+};
+
+use anyhow::{anyhow, Error, Result};
+use serde::Serialize;
+use sled::{transaction::ConflictableTransactionError, Db, Transactional, Tree};
+use sled_ext::{key::Key, value::Value};
+use tokio::sync::watch;
+
+use crate::{
+	model::{Filter, Filters, Subscription, SubscriptionId, SubscriptionIds},
+	util::now,
+};
+
+/// Tree holding every subscription under its ID.
+pub const SUBSCRIPTIONS_TREE: &str = "subscriptions";
+
+/// Subscriptions and the filters they're registered under, mirrored in memory so matching a
+/// killmail is a hash lookup. All writes go through here so sled and the mirror stay in sync.
+#[derive(Debug)]
+pub struct Index {
+	db: Db,
+	subscriptions: Tree,
+	filters: Tree,
+	cache: RwLock<Cache>,
+	/// Filters of connected streaming clients. They aren't stored, but their killmails still
+	/// have to be received.
+	streams: Mutex<HashMap<usize, Arc<HashSet<Filter>>>>,
+	next_stream: AtomicUsize,
+	/// Notified whenever the set of wanted filters changes.
+	changed: (watch::Sender<()>, watch::Receiver<()>),
+}
+
+/// Keeps a streaming client's filters wanted until it's dropped.
+#[derive(Debug)]
+pub struct Tracked {
+	index: Arc<Index>,
+	id: usize,
+}
+
+impl Drop for Tracked {
+	fn drop(&mut self) {
+		self.index.streams.lock().unwrap().remove(&self.id);
+		self.index.notify();
+	}
+}
+
+/// A subscription with its ID and the filters it's registered under.
+#[derive(Debug, Serialize, Clone)]
+pub struct Entry {
+	pub id: SubscriptionId,
+	pub subscription: Subscription,
+	pub filters: HashSet<Filter>,
+}
+
+#[derive(Debug, Default)]
+struct Cache {
+	subscriptions: HashMap<SubscriptionId, Subscription>,
+	filters: HashMap<Filter, SubscriptionIds>,
+}
+
+/// Writes computed against the cache, applied together by [`Index::apply`].
+#[derive(Debug, Default)]
+struct Changes {
+	/// `None` removes the subscription.
+	subscriptions: HashMap<SubscriptionId, Option<Subscription>>,
+	/// Empty sets remove the filter.
+	filters: HashMap<Filter, SubscriptionIds>,
+}
+
+fn read<K>(tree: &Tree) -> Result<HashMap<K, K::Value>>
+where
+	K: Key<Error = Error> + Eq + Hash,
+	K::Value: Value<Error = Error>,
+{
+	tree.iter()
+		.map(|entry| {
+			let (key, value) = entry?;
+			Ok((K::from_bytes(&key)?, K::Value::from_bytes(&value)?))
+		})
+		.collect()
+}
+
+impl Cache {
+	fn find(&self, sub: &Subscription) -> Option<SubscriptionId> {
+		self.subscriptions
+			.iter()
+			.find(|(_, existing)| *existing == sub)
+			.map(|(id, _)| *id)
+	}
+
+	fn entries(&self) -> HashMap<SubscriptionId, Entry> {
+		let mut entries = self
+			.subscriptions
+			.iter()
+			.map(|(id, sub)| {
+				let entry = Entry {
+					id: *id,
+					subscription: sub.clone(),
+					filters: HashSet::new(),
+				};
+				(*id, entry)
+			})
+			.collect::<HashMap<_, _>>();
+
+		for (filter, ids) in &self.filters {
+			for id in ids.iter() {
+				if let Some(entry) = entries.get_mut(id) {
+					entry.filters.insert(filter.clone());
+				}
+			}
+		}
+
+		entries
+	}
+}
+
+impl Cache {
+	fn needs_killstream(&self) -> bool {
+		let now = now();
+		self.subscriptions
+			.values()
+			.any(|sub| sub.battles && sub.is_active(now))
+	}
+}
+
+impl Changes {
+	/// The subscription IDs the filter will have, starting from those in the cache.
+	fn ids(&mut self, cache: &Cache, filter: &Filter) -> &mut SubscriptionIds {
+		self.filters
+			.entry(filter.clone())
+			.or_insert_with(|| cache.filters.get(filter).cloned().unwrap_or_default())
+	}
+
+	/// Remove the subscription from every filter it's registered under.
+	fn unlink(&mut self, cache: &Cache, id: SubscriptionId) {
+		for (filter, ids) in &cache.filters {
+			if ids.contains(&id) {
+				self.ids(cache, filter).remove(&id);
+			}
+		}
+	}
+
+	fn is_empty(&self) -> bool {
+		self.subscriptions.is_empty() && self.filters.is_empty()
+	}
+}
+
+impl Index {
+	/// Read every subscription and filter into memory.
+	pub fn load(db: &Db, tree: &str) -> Result<Self> {
+		let subscriptions = db.open_tree(SUBSCRIPTIONS_TREE)?;
+		let filters = db.open_tree(tree)?;
+		let cache = Cache {
+			subscriptions: read(&subscriptions)?,
+			filters: read(&filters)?,
+		};
+
+		Ok(Self {
+			db: db.clone(),
+			subscriptions,
+			filters,
+			cache: RwLock::new(cache),
+			streams: Mutex::default(),
+			next_stream: AtomicUsize::new(0),
+			changed: watch::channel(()),
+		})
+	}
+
+	/// Every filter with a subscription or a streaming client.
+	pub fn interest(&self) -> HashSet<Filter> {
+		let mut filters = self
+			.cache
+			.read()
+			.unwrap()
+			.filters
+			.keys()
+			.cloned()
+			.collect::<HashSet<_>>();
+
+		for stream in self.streams.lock().unwrap().values() {
+			filters.extend(stream.iter().cloned());
+		}
+
+		filters
+	}
+
+	/// Whether any filter in [`Index::interest`] satisfies the predicate.
+	pub fn wants(&self, predicate: impl Fn(&Filter) -> bool) -> bool {
+		let cache = self.cache.read().unwrap();
+		cache.filters.keys().any(&predicate)
+			|| self
+				.streams
+				.lock()
+				.unwrap()
+				.values()
+				.any(|filters| filters.iter().any(&predicate))
+	}
+
+	/// Whether an active subscription reports battles. Battles are pieced together from every kill
+	/// in a system, which channels narrowed to the filters would miss.
+	pub fn needs_killstream(&self) -> bool {
+		self.cache.read().unwrap().needs_killstream()
+	}
+
+	/// A receiver notified when [`Index::interest`] or [`Index::needs_killstream`] may have
+	/// changed.
+	pub fn changes(&self) -> watch::Receiver<()> {
+		self.changed.1.clone()
+	}
+
+	/// Count a streaming client's filters in [`Index::interest`] while the guard is held.
+	pub fn track(self: &Arc<Self>, filters: Arc<HashSet<Filter>>) -> Tracked {
+		let id = self.next_stream.fetch_add(1, Ordering::Relaxed);
+		self.streams.lock().unwrap().insert(id, filters);
+		self.notify();
+
+		Tracked {
+			index: Arc::clone(self),
+			id,
+		}
+	}
+
+	fn notify(&self) {
+		// the index holds a receiver itself, so this can't fail
+		let _ = self.changed.0.send(());
+	}
+
+	/// Subscriptions registered under the filter.
+	pub fn get(&self, filter: &Filter) -> Vec<(SubscriptionId, Subscription)> {
+		let cache = self.cache.read().unwrap();
+
+		cache
+			.filters
+			.get(filter)
+			.map(|ids| {
+				ids.iter()
+					.filter_map(|id| Some((*id, cache.subscriptions.get(id)?.clone())))
+					.collect()
+			})
+			.unwrap_or_default()
+	}
+
+	/// The subscription with the ID, if it's still registered.
+	pub fn subscription(&self, id: SubscriptionId) -> Option<Subscription> {
+		self.cache.read().unwrap().subscriptions.get(&id).cloned()
+	}
+
+	/// Every subscription with its filters, ordered by ID.
+	pub fn entries(&self) -> Vec<Entry> {
+		let mut entries = self
+			.cache
+			.read()
+			.unwrap()
+			.entries()
+			.into_iter()
+			.map(|(_, entry)| entry)
+			.collect::<Vec<_>>();
+		entries.sort_by_key(|entry| entry.id);
+		entries
+	}
+
+	/// A copy of every filter and its subscriptions.
+	pub fn filters(&self) -> Filters {
+		let cache = self.cache.read().unwrap();
+
+		cache
+			.filters
+			.iter()
+			.map(|(filter, ids)| {
+				let subs = ids
+					.iter()
+					.filter_map(|id| cache.subscriptions.get(id).cloned())
+					.collect();
+				(filter.clone(), subs)
+			})
+			.collect()
+	}
+
+	/// Register subscriptions under their filters. A subscription identical to one already
+	/// registered is added to that one's filters instead of getting a new ID. Returns each
+	/// subscription with its ID.
+	pub fn subscribe(&self, filters: &Filters) -> Result<Vec<(SubscriptionId, Subscription)>> {
+		let mut cache = self.cache.write().unwrap();
+		let mut changes = Changes::default();
+
+		let registered = self.register(&cache, &mut changes, filters)?;
+		self.apply(&mut cache, changes)?;
+		Ok(registered)
+	}
+
+	/// Replace every filter and subscription. Subscriptions get new IDs.
+	pub fn replace(&self, filters: Filters) -> Result<()> {
+		let mut cache = self.cache.write().unwrap();
+		let mut changes = Changes::default();
+
+		for id in cache.subscriptions.keys() {
+			changes.subscriptions.insert(*id, None);
+		}
+
+		for filter in cache.filters.keys() {
+			changes
+				.filters
+				.insert(filter.clone(), SubscriptionIds::default());
+		}
+
+		self.register(&Cache::default(), &mut changes, &filters)?;
+		self.apply(&mut cache, changes)
+	}
+
+	/// Remove a subscription from every filter. Returns whether it existed.
+	pub fn remove(&self, id: SubscriptionId) -> Result<bool> {
+		let mut cache = self.cache.write().unwrap();
+
+		if !cache.subscriptions.contains_key(&id) {
+			return Ok(false);
+		}
+
+		let mut changes = Changes::default();
+		changes.unlink(&cache, id);
+		changes.subscriptions.insert(id, None);
+
+		self.apply(&mut cache, changes)?;
+		Ok(true)
+	}
+
+	/// Modify a subscription and, if given, replace the filters it's registered under, all in one
+	/// transaction. Returns whether it existed.
+	pub fn patch<F>(
+		&self,
+		id: SubscriptionId,
+		f: F,
+		filters: Option<HashSet<Filter>>,
+	) -> Result<bool>
+	where
+		F: FnOnce(&mut Subscription),
+	{
+		let mut cache = self.cache.write().unwrap();
+
+		let mut sub = match cache.subscriptions.get(&id) {
+			Some(sub) => sub.clone(),
+			None => return Ok(false),
+		};
+		f(&mut sub);
+
+		let mut changes = Changes::default();
+		changes.subscriptions.insert(id, Some(sub));
+
+		if let Some(filters) = filters {
+			changes.unlink(&cache, id);
+			for filter in &filters {
+				changes.ids(&cache, filter).insert(id);
+			}
+		}
+
+		self.apply(&mut cache, changes)?;
+		Ok(true)
+	}
+
+	/// Replace every subscription with the result of `f`, removing it if `f` returns `None`.
+	/// Filters left without subscriptions are removed. Returns how many subscriptions changed.
+	pub fn update<F>(&self, f: F) -> Result<usize>
+	where
+		F: Fn(&Subscription) -> Option<Subscription>,
+	{
+		let mut cache = self.cache.write().unwrap();
+		let mut changes = Changes::default();
+
+		for (id, sub) in &cache.subscriptions {
+			let new = f(sub);
+			if new.as_ref() == Some(sub) {
+				continue;
+			}
+
+			if new.is_none() {
+				changes.unlink(&cache, *id);
+			}
+			changes.subscriptions.insert(*id, new);
+		}
+
+		let changed = changes.subscriptions.len();
+		self.apply(&mut cache, changes)?;
+		Ok(changed)
+	}
+
+	/// Add each subscription to `changes` under its filters, reusing the ID of an identical
+	/// subscription in `cache`.
+	fn register(
+		&self,
+		cache: &Cache,
+		changes: &mut Changes,
+		filters: &Filters,
+	) -> Result<Vec<(SubscriptionId, Subscription)>> {
+		let mut registered = HashMap::new();
+
+		for (filter, subs) in filters {
+			for sub in subs.iter() {
+				let id = match registered.get(sub).copied().or_else(|| cache.find(sub)) {
+					Some(id) => id,
+					None => {
+						let id = SubscriptionId(self.db.generate_id()?);
+						changes.subscriptions.insert(id, Some(sub.clone()));
+						id
+					}
+				};
+
+				registered.insert(sub.clone(), id);
+				changes.ids(cache, filter).insert(id);
+			}
+		}
+
+		Ok(registered.into_iter().map(|(sub, id)| (id, sub)).collect())
+	}
+
+	/// Write the changes to sled in one transaction, then to the cache.
+	fn apply(&self, cache: &mut Cache, changes: Changes) -> Result<()> {
+		if changes.is_empty() {
+			return Ok(());
+		}
+
+		(&self.subscriptions, &self.filters)
+			.transaction(|(subscriptions, filters)| {
+				for (id, sub) in &changes.subscriptions {
+					match sub {
+						Some(sub) => id.insert(subscriptions, sub.clone()).unwrap(),
+						None => id.remove(subscriptions).unwrap(),
+					};
+				}
+
+				for (filter, ids) in &changes.filters {
+					if ids.is_empty() {
+						filter.remove(filters).unwrap();
+					} else {
+						filter.insert(filters, ids.clone()).unwrap();
+					}
+				}
+
+				Ok::<_, ConflictableTransactionError<Error>>(())
+			})
+			.map_err(|e| anyhow!("{}", e))?;
+
+		let needed_killstream = cache.needs_killstream();
+		for (id, sub) in changes.subscriptions {
+			match sub {
+				Some(sub) => cache.subscriptions.insert(id, sub),
+				None => cache.subscriptions.remove(&id),
+			};
+		}
+
+		let filters_changed = !changes.filters.is_empty();
+		for (filter, ids) in changes.filters {
+			if ids.is_empty() {
+				cache.filters.remove(&filter);
+			} else {
+				cache.filters.insert(filter, ids);
+			}
+		}
+
+		if filters_changed || cache.needs_killstream() != needed_killstream {
+			self.notify();
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashSet;
+
+	use sled::Db;
+
+	use super::Index;
+	use crate::model::{
+		Filter, Filters, Format, Involvement, Perspective, Role, Subscription, SubscriptionId,
+	};
+
+	const TREE: &str = "webhooks";
+
+	fn open() -> Db {
+		sled::Config::new().temporary(true).open().unwrap()
+	}
+
+	fn sub(url: &str) -> Subscription {
+		Subscription {
+			webhook_url: url.into(),
+			format: Format::Discord,
+			digest: None,
+			paused: false,
+			expires_at: None,
+			friendly: Perspective::default(),
+			battles: false,
+		}
+	}
+
+	fn character(id: usize) -> Filter {
+		Filter::Character(Involvement {
+			id,
+			role: Role::Victim,
+		})
+	}
+
+	fn filters(entries: &[(Filter, &[&Subscription])]) -> Filters {
+		entries
+			.iter()
+			.map(|(filter, subs)| {
+				(
+					filter.clone(),
+					subs.iter().map(|&sub| sub.clone()).collect(),
+				)
+			})
+			.collect()
+	}
+
+	fn set(filters: &[Filter]) -> HashSet<Filter> {
+		filters.iter().cloned().collect()
+	}
+
+	/// The filters of each subscription, checking the stored copy matches the one in memory.
+	fn entries(db: &Db, index: &Index) -> Vec<(SubscriptionId, Subscription, HashSet<Filter>)> {
+		let entries = |index: &Index| {
+			index
+				.entries()
+				.into_iter()
+				.map(|entry| (entry.id, entry.subscription, entry.filters))
+				.collect::<Vec<_>>()
+		};
+
+		let cached = entries(index);
+		assert_eq!(cached, entries(&Index::load(db, TREE).unwrap()));
+		cached
+	}
+
+	#[test]
+	fn subscribes_identical_subscriptions_once() {
+		let db = open();
+		let index = Index::load(&db, TREE).unwrap();
+		let a = sub("https://example.com/a");
+
+		let registered = index
+			.subscribe(&filters(&[(character(1), &[&a]), (Filter::All, &[&a])]))
+			.unwrap();
+		assert_eq!(registered.len(), 1);
+		let id = registered[0].0;
+
+		// registering it again adds the new filter to the same ID
+		let again = index.subscribe(&filters(&[(character(2), &[&a])])).unwrap();
+		assert_eq!(again, vec![(id, a.clone())]);
+
+		assert_eq!(
+			entries(&db, &index),
+			vec![(id, a, set(&[character(1), character(2), Filter::All]))]
+		);
+	}
+
+	#[test]
+	fn keys_edited_subscriptions_by_id() {
+		let db = open();
+		let index = Index::load(&db, TREE).unwrap();
+		let a = sub("https://example.com/a");
+
+		let id = index.subscribe(&filters(&[(character(1), &[&a])])).unwrap()[0].0;
+		assert!(index.patch(id, |sub| sub.paused = true, None).unwrap());
+
+		// the edited subscription keeps its ID, so the original shape is new again
+		let other = index.subscribe(&filters(&[(character(1), &[&a])])).unwrap()[0].0;
+		assert_ne!(other, id);
+
+		let paused = Subscription {
+			paused: true,
+			..a.clone()
+		};
+		assert_eq!(index.subscription(id).as_ref(), Some(&paused));
+		assert_eq!(index.get(&character(1)).len(), 2);
+
+		// and registering the edited shape finds it by its ID
+		let edited = index
+			.subscribe(&filters(&[(Filter::All, &[&paused])]))
+			.unwrap();
+		assert_eq!(edited[0].0, id);
+	}
+
+	#[test]
+	fn patches_subscriptions_and_their_filters() {
+		let db = open();
+		let index = Index::load(&db, TREE).unwrap();
+		let (a, b) = (sub("https://example.com/a"), sub("https://example.com/b"));
+
+		let registered = index
+			.subscribe(&filters(&[
+				(character(1), &[&a, &b]),
+				(character(2), &[&a]),
+			]))
+			.unwrap();
+		let id = |sub: &Subscription| registered.iter().find(|(_, s)| s == sub).unwrap().0;
+
+		let patched = index
+			.patch(
+				id(&a),
+				|sub| sub.format = Format::Raw,
+				Some(set(&[character(3)])),
+			)
+			.unwrap();
+		assert!(patched);
+
+		let raw = Subscription {
+			format: Format::Raw,
+			..a.clone()
+		};
+		let mut expected = vec![
+			(id(&a), raw, set(&[character(3)])),
+			(id(&b), b.clone(), set(&[character(1)])),
+		];
+		expected.sort_by_key(|(id, _, _)| *id);
+		assert_eq!(entries(&db, &index), expected);
+
+		// the filter left without subscriptions is gone
+		assert!(!index.interest().contains(&character(2)));
+
+		assert!(!index.patch(SubscriptionId(u64::MAX), |_| {}, None).unwrap());
+	}
+
+	#[test]
+	fn replaces_everything() {
+		let db = open();
+		let index = Index::load(&db, TREE).unwrap();
+		let (a, b) = (sub("https://example.com/a"), sub("https://example.com/b"));
+
+		let old = index.subscribe(&filters(&[(character(1), &[&a])])).unwrap()[0].0;
+		index
+			.replace(filters(&[(character(2), &[&a]), (Filter::All, &[&b])]))
+			.unwrap();
+
+		let entries = entries(&db, &index);
+		assert_eq!(entries.len(), 2);
+		assert!(entries.iter().all(|(id, _, _)| *id != old));
+		assert_eq!(index.interest(), set(&[character(2), Filter::All]));
+		assert_eq!(index.get(&character(2))[0].1, a);
+		assert_eq!(index.get(&Filter::All)[0].1, b);
+	}
+
+	#[test]
+	fn removes_subscriptions_from_every_filter() {
+		let db = open();
+		let index = Index::load(&db, TREE).unwrap();
+		let (a, b) = (sub("https://example.com/a"), sub("https://example.com/b"));
+
+		let registered = index
+			.subscribe(&filters(&[(character(1), &[&a, &b]), (Filter::All, &[&a])]))
+			.unwrap();
+		let id = |sub: &Subscription| registered.iter().find(|(_, s)| s == sub).unwrap().0;
+
+		assert!(index.remove(id(&a)).unwrap());
+		assert!(!index.remove(id(&a)).unwrap());
+
+		assert_eq!(
+			entries(&db, &index),
+			vec![(id(&b), b, set(&[character(1)]))]
+		);
+		assert_eq!(index.interest(), set(&[character(1)]));
+		assert_eq!(index.subscription(id(&a)), None);
+	}
+}
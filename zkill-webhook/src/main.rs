@@ -1,44 +1,115 @@
-use std::{env, net::SocketAddr};
+use std::{env, sync::Arc};
 
-use anyhow::{Error, Result};
-use axum::{extract::Extension, routing::post, AddExtensionLayer, Router};
-use axum_msgpack::MsgPack;
-use model::Filters;
-use reqwest::{Client, StatusCode};
+use anyhow::Result;
+use axum::{
+	routing::{get, patch, post},
+	AddExtensionLayer, Router,
+};
+use config::Config;
+use delivery::Delivery;
+use esi::Esi;
+use futures::future;
+use index::Index;
+use model::zkb::Killmail;
+use notable::Baselines;
+use reqwest::Client;
 use sled::Tree;
-use sled_ext::key::Key;
-use tokio::spawn;
+use tokio::{
+	select, signal, spawn,
+	sync::{
+		broadcast,
+		mpsc::{channel, Sender},
+		watch,
+	},
+	task::JoinHandle,
+	time::{timeout_at, Instant},
+};
 use tower_http::trace::TraceLayer;
-use tracing::log::error;
+use tracing::log::{error, info, warn};
 
+mod activity;
+mod admin;
+mod backup;
+mod battle;
+mod config;
+mod delivery;
+mod delivery_log;
+mod digest;
+mod esi;
+mod expiry;
+mod format;
+mod index;
 mod model;
-mod ws;
+mod notable;
+mod routes;
+mod source;
+mod stats;
+mod storage;
+mod template;
+mod util;
 
 #[derive(Debug, Clone)]
 pub struct State {
 	pub client: Client,
-	pub tree: Tree,
+	pub index: Arc<Index>,
+	pub digests: Tree,
+	pub delivery_log: Tree,
+	pub esi: Arc<Esi>,
+	/// Daily kill and loss counts per entity.
+	pub stats: Tree,
+	/// Hour of day and day of week activity per character, corporation and alliance.
+	pub activity: Tree,
+	/// Battles still in progress, by system.
+	pub battles: Tree,
+	/// Recent kill values per ship group.
+	pub baselines: Arc<Baselines>,
+	pub queue: Sender<Delivery>,
+	/// Every processed killmail, for streaming clients.
+	pub killmails: broadcast::Sender<Arc<Killmail>>,
+	pub shutdown: watch::Receiver<bool>,
+	pub config: Arc<Config>,
 }
 
-async fn register_webhook(state: Extension<State>, MsgPack(body): MsgPack<Filters>) -> StatusCode {
-	if body.is_empty() {
-		return StatusCode::BAD_REQUEST;
+/// Resolve when the process is asked to stop with SIGINT or SIGTERM.
+async fn shutdown_signal() {
+	let interrupt = async {
+		if let Err(e) = signal::ctrl_c().await {
+			error!("Unable to listen for SIGINT: {}", e);
+			future::pending::<()>().await;
+		}
+	};
+
+	#[cfg(unix)]
+	let terminate = async {
+		match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+			Ok(mut stream) => {
+				stream.recv().await;
+			}
+			Err(e) => {
+				error!("Unable to listen for SIGTERM: {}", e);
+				future::pending::<()>().await;
+			}
+		}
+	};
+
+	#[cfg(not(unix))]
+	let terminate = future::pending::<()>();
+
+	select! {
+		_ = interrupt => {},
+		_ = terminate => {},
 	}
+}
 
-	let res = state.tree.transaction::<_, _, Error>(move |txn| {
-		for (filter, sub) in &body {
-			let mut existing = filter.get(txn).unwrap().unwrap_or_default();
-			existing.extend(sub.iter().cloned());
-			filter.insert(txn, existing).unwrap();
+/// Wait for a task until the deadline, aborting it if it hasn't finished by then.
+async fn finish(name: &str, mut task: JoinHandle<()>, deadline: Instant) {
+	match timeout_at(deadline, &mut task).await {
+		Ok(Ok(())) => {}
+		Ok(Err(e)) => error!("The {} task failed: {}", name, e),
+		Err(_) => {
+			warn!("Timed out waiting for {} to finish", name);
+			task.abort();
 		}
-		Ok(())
-	});
-
-	if let Err(e) = &res {
-		error!("{}", e);
-		StatusCode::INTERNAL_SERVER_ERROR
-	} else {
-		StatusCode::NO_CONTENT
 	}
 }
 
@@ -46,36 +117,120 @@ async fn register_webhook(state: Extension<State>, MsgPack(body): MsgPack<Filter
 async fn main() -> Result<()> {
 	tracing_subscriber::fmt::init();
 
-	let db = sled::open("data")?;
-	let tree = db.open_tree("webhooks")?;
-	let client = Client::new();
-	let state = State { tree, client };
+	let config = Config::load()?;
 
-	let ws_state = state.clone();
-	spawn(async move {
-		loop {
-			if let Err(e) = ws::run(ws_state.clone()).await {
-				error!("{}", e);
-			}
-		}
-	});
+	let args = env::args().skip(1).collect::<Vec<_>>();
+	if let Some(("admin", args)) = args.split_first().map(|(cmd, rest)| (cmd.as_str(), rest)) {
+		return admin::run(&config, args);
+	}
+
+	let db = sled::open(&config.db.path)?;
+	storage::migrate(&db, &config)?;
+
+	let index = Index::load(&db, &config.db.tree)?;
+	let digests = db.open_tree("digests")?;
+	let delivery_log = db.open_tree("delivery_log")?;
+	let stats = db.open_tree("stats")?;
+	let activity = db.open_tree("activity")?;
+	let battles = db.open_tree("battles")?;
+	let baselines = Baselines::load(db.open_tree("baselines")?)?;
+	let client = Client::builder()
+		.timeout(config.delivery.timeout())
+		.connect_timeout(config.delivery.connect_timeout())
+		.build()?;
+	let esi = Esi {
+		client: Client::builder()
+			.timeout(config.esi.timeout())
+			.connect_timeout(config.delivery.connect_timeout())
+			.build()?,
+		url: config.esi.url.clone(),
+		names: db.open_tree("names")?,
+		name_ttl: config.esi.name_ttl,
+		killmails: db.open_tree("killmails")?,
+		types: db.open_tree("types")?,
+	};
+	let (queue, pending) = channel(config.delivery.queue_size);
+	let (killmails, _) = broadcast::channel(config.stream.buffer);
+	let (stop, shutdown) = watch::channel(false);
+	let state = State {
+		index: Arc::new(index),
+		digests,
+		delivery_log,
+		esi: Arc::new(esi),
+		stats,
+		activity,
+		battles,
+		baselines: Arc::new(baselines),
+		client,
+		queue,
+		killmails,
+		shutdown: shutdown.clone(),
+		config: Arc::new(config),
+	};
+
+	// deliveries drain separately, once ingestion has stopped adding to the queue
+	let (drain, draining) = watch::channel(false);
+	let deliveries = spawn(delivery::run(state.clone(), pending, draining));
+
+	let ingestion = spawn(source::run(
+		state.clone(),
+		source::from_config(&state.config, &state.index),
+		shutdown.clone(),
+	));
+
+	let background = vec![
+		("digests", spawn(digest::run(state.clone()))),
+		("expiry", spawn(expiry::run(state.clone()))),
+		("stats", spawn(stats::run(state.clone()))),
+		("activity", spawn(activity::run(state.clone()))),
+		("battles", spawn(battle::run(state.clone()))),
+		("delivery logs", spawn(delivery_log::run(state.clone()))),
+		("baselines", spawn(notable::run(state.clone()))),
+	];
+	let baselines = Arc::clone(&state.baselines);
 
 	let app = Router::new()
-		.route("/", post(register_webhook))
+		.route("/", post(routes::register_webhook))
+		.route("/subscriptions/:id", patch(routes::update_subscription))
+		.route("/subscriptions/pause", post(routes::pause))
+		.route("/subscriptions/resume", post(routes::resume))
+		.route("/subscriptions/expiry", post(routes::set_expiry))
+		.route("/stream", get(routes::stream))
+		.route("/deliveries", get(routes::deliveries))
+		.route("/stats/:kind/:id", get(routes::stats))
+		.route("/activity/:kind/:id", get(routes::activity))
+		.route("/admin/dead-letters", get(routes::dead_letters))
+		.route("/admin/export", get(routes::export))
+		.route("/admin/import", post(routes::import))
 		.layer(TraceLayer::new_for_http())
 		.layer(AddExtensionLayer::new(state));
 
-	let addr = SocketAddr::from((
-		[0, 0, 0, 0],
-		env::var("PORT")
-			.ok()
-			.and_then(|port| port.parse().ok())
-			.unwrap_or(3000),
-	));
+	let addr = state.config.listen;
+	let shutdown_timeout = state.config.delivery.shutdown_timeout();
 
 	axum::Server::bind(&addr)
 		.serve(app.into_make_service())
+		.with_graceful_shutdown(async move {
+			shutdown_signal().await;
+			info!("Shutting down");
+			let _ = stop.send(true);
+		})
 		.await?;
 
+	// everything shares one deadline; ingestion goes first since it feeds the delivery queue
+	let deadline = Instant::now() + shutdown_timeout;
+	finish("ingestion", ingestion, deadline).await;
+	let _ = drain.send(true);
+	finish("deliveries", deliveries, deadline).await;
+	for (name, task) in background {
+		finish(name, task, deadline).await;
+	}
+
+	if let Err(e) = baselines.flush() {
+		error!("Error saving notable baselines: {}", e);
+	}
+
+	db.flush_async().await?;
+
 	Ok(())
 }
@@ -1,17 +1,22 @@
 use std::{
-	collections::{HashMap, HashSet},
+	collections::{BTreeSet, HashMap, HashSet},
 	convert::TryFrom,
 	hash::Hash,
 	iter::FromIterator,
 	ops::{Deref, DerefMut},
 };
 
-use anyhow::{Context, Error, Result};
-use bincode::{deserialize, serialize};
+use anyhow::{ensure, Context, Error, Result};
 use serde::{Deserialize, Serialize};
 use sled::IVec;
 use sled_ext::{key::Key, value::Value};
 
+use crate::{
+	storage::{decode, encode},
+	template::Template,
+};
+use zkb::Killmail;
+
 pub mod zkb;
 
 // { { "hello": "world" }: { "foo": "bar" } }
@@ -25,6 +30,23 @@ pub enum Filter {
 	Alliance(Involvement),
 	System(usize),
 	Ship(Involvement),
+	/// Kills worth more than this percentile of recent kills of the same ship group.
+	Notable {
+		percentile: u8,
+	},
+}
+
+impl Filter {
+	/// Check that the filter can match killmails.
+	pub fn validate(&self) -> Result<()> {
+		match self {
+			Self::Notable { percentile } => {
+				ensure!(*percentile < 100, "Percentile must be below 100");
+				Ok(())
+			}
+			_ => Ok(()),
+		}
+	}
 }
 
 impl Default for Filter {
@@ -33,17 +55,58 @@ impl Default for Filter {
 	}
 }
 
+/// `Filter` is internally tagged for the API, which bincode can't deserialize, so it's stored
+/// through this externally tagged mirror.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StoredFilter {
+	All,
+	Character(Involvement),
+	Corporation(Involvement),
+	Alliance(Involvement),
+	System(usize),
+	Ship(Involvement),
+	Notable { percentile: u8 },
+}
+
+impl From<&Filter> for StoredFilter {
+	fn from(filter: &Filter) -> Self {
+		match filter.clone() {
+			Filter::All => Self::All,
+			Filter::Character(involvement) => Self::Character(involvement),
+			Filter::Corporation(involvement) => Self::Corporation(involvement),
+			Filter::Alliance(involvement) => Self::Alliance(involvement),
+			Filter::System(id) => Self::System(id),
+			Filter::Ship(involvement) => Self::Ship(involvement),
+			Filter::Notable { percentile } => Self::Notable { percentile },
+		}
+	}
+}
+
+impl From<StoredFilter> for Filter {
+	fn from(filter: StoredFilter) -> Self {
+		match filter {
+			StoredFilter::All => Self::All,
+			StoredFilter::Character(involvement) => Self::Character(involvement),
+			StoredFilter::Corporation(involvement) => Self::Corporation(involvement),
+			StoredFilter::Alliance(involvement) => Self::Alliance(involvement),
+			StoredFilter::System(id) => Self::System(id),
+			StoredFilter::Ship(involvement) => Self::Ship(involvement),
+			StoredFilter::Notable { percentile } => Self::Notable { percentile },
+		}
+	}
+}
+
 impl Key for Filter {
-	type Value = Subscriptions;
+	type Value = SubscriptionIds;
 
 	type Error = Error;
 
 	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
-		Ok(bincode::deserialize(bytes)?)
+		Ok(decode::<StoredFilter>(bytes)?.into())
 	}
 
 	fn to_bytes(&self) -> Result<IVec, Self::Error> {
-		Ok(bincode::serialize(self)?.into())
+		encode(&StoredFilter::from(self))
 	}
 }
 
@@ -51,9 +114,9 @@ impl TryFrom<Filter> for IVec {
 	type Error = Error;
 
 	fn try_from(value: Filter) -> Result<Self, Self::Error> {
-		Ok(serialize(&value)
-			.context("Failed to serialize Filter into bincode")?
-			.into())
+		value
+			.to_bytes()
+			.context("Failed to serialize Filter into bincode")
 	}
 }
 
@@ -61,7 +124,7 @@ impl TryFrom<IVec> for Filter {
 	type Error = Error;
 
 	fn try_from(value: IVec) -> Result<Self, Self::Error> {
-		deserialize(&*value).context("Failed to read Filter from bincode")
+		Self::from_bytes(&value).context("Failed to read Filter from bincode")
 	}
 }
 
@@ -77,16 +140,202 @@ pub enum Role {
 	Victim,
 }
 
+/// Stable identifier of a subscription, generated when it's first registered.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy)]
+#[serde(transparent)]
+pub struct SubscriptionId(pub u64);
+
+impl Key for SubscriptionId {
+	type Value = Subscription;
+
+	type Error = Error;
+
+	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
+		decode(bytes)
+	}
+
+	fn to_bytes(&self) -> Result<IVec, Self::Error> {
+		encode(self)
+	}
+}
+
+/// Where and how to deliver matching killmails.
+///
+/// The `serde(default)`s only let API clients leave fields out. bincode doesn't store field names,
+/// so they don't help read older stored subscriptions; adding a field needs a migration in
+/// [`crate::storage`].
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
 pub struct Subscription {
 	pub webhook_url: String,
 	pub format: Format,
+	/// Collect matching kills and post a summary every this many minutes instead of posting each
+	/// kill individually.
+	#[serde(default)]
+	pub digest: Option<u64>,
+	/// Skip deliveries while set.
+	#[serde(default)]
+	pub paused: bool,
+	/// Unix timestamp after which the subscription is removed.
+	#[serde(default)]
+	pub expires_at: Option<u64>,
+	/// Whose side the subscription is on, to tell kills from losses.
+	#[serde(default)]
+	pub friendly: Perspective,
+	/// Post a report of each battle the matching kills were part of instead of each kill.
+	#[serde(default)]
+	pub battles: bool,
+}
+
+/// Characters, corporations and alliances a subscription counts as its own.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Default)]
+pub struct Perspective {
+	#[serde(default)]
+	pub characters: BTreeSet<usize>,
+	#[serde(default)]
+	pub corporations: BTreeSet<usize>,
+	#[serde(default)]
+	pub alliances: BTreeSet<usize>,
+}
+
+/// What a killmail was from a subscription's side.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+	/// A friendly attacker killed someone else.
+	Kill,
+	/// Someone else killed a friendly victim.
+	Loss,
+	/// A friendly attacker killed a friendly victim.
+	Awox,
+}
+
+impl Perspective {
+	pub fn is_empty(&self) -> bool {
+		self.characters.is_empty() && self.corporations.is_empty() && self.alliances.is_empty()
+	}
+
+	fn contains(
+		&self,
+		character: Option<usize>,
+		corporation: Option<usize>,
+		alliance: Option<usize>,
+	) -> bool {
+		let any =
+			|ids: &BTreeSet<usize>, id: Option<usize>| id.map_or(false, |id| ids.contains(&id));
+
+		any(&self.characters, character)
+			|| any(&self.corporations, corporation)
+			|| any(&self.alliances, alliance)
+	}
+
+	/// Classify the killmail, or `None` if nobody friendly was involved.
+	pub fn classify(&self, km: &Killmail) -> Option<Outcome> {
+		let victim = &km.victim;
+		let lost = self.contains(
+			victim.character_id,
+			Some(victim.corporation_id),
+			victim.alliance_id,
+		);
+		let killed = km.attackers.iter().any(|attacker| {
+			self.contains(
+				attacker.character_id,
+				attacker.corporation_id,
+				attacker.alliance_id,
+			)
+		});
+
+		match (killed, lost) {
+			(true, true) => Some(Outcome::Awox),
+			(true, false) => Some(Outcome::Kill),
+			(false, true) => Some(Outcome::Loss),
+			(false, false) => None,
+		}
+	}
+}
+
+impl Outcome {
+	pub fn label(&self) -> &'static str {
+		match self {
+			Self::Kill => "KILL",
+			Self::Loss => "LOSS",
+			Self::Awox => "AWOX",
+		}
+	}
+
+	/// RGB colour to show the outcome in.
+	pub fn color(&self) -> u32 {
+		match self {
+			Self::Kill => 0x2e_cc_71,
+			Self::Loss => 0xe7_4c_3c,
+			Self::Awox => 0xe6_7e_22,
+		}
+	}
+}
+
+impl Value for Subscription {
+	type Error = Error;
+
+	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
+		decode(bytes)
+	}
+
+	fn to_bytes(&self) -> Result<IVec, Self::Error> {
+		encode(self)
+	}
+}
+
+impl Subscription {
+	pub fn is_expired(&self, now: u64) -> bool {
+		self.expires_at
+			.map_or(false, |expires_at| expires_at <= now)
+	}
+
+	/// Whether killmails should be delivered to this subscription.
+	pub fn is_active(&self, now: u64) -> bool {
+		!self.paused && !self.is_expired(now)
+	}
+
+	/// What the killmail was from this subscription's side, if it says whose side it's on.
+	pub fn classify(&self, km: &Killmail) -> Option<Outcome> {
+		self.friendly.classify(km)
+	}
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
 pub enum Format {
 	Raw,
 	Discord,
+	Template(Template),
+	/// A Slack incoming webhook.
+	Slack,
+	/// A Mattermost incoming webhook.
+	Mattermost,
+	/// The Matrix client-server send-message endpoint, i.e.
+	/// `{homeserver}/_matrix/client/v3/rooms/{room_id}/send/m.room.message`.
+	Matrix {
+		access_token: String,
+	},
+}
+
+impl Format {
+	pub fn name(&self) -> &'static str {
+		match self {
+			Self::Raw => "Raw",
+			Self::Discord => "Discord",
+			Self::Template(_) => "Template",
+			Self::Slack => "Slack",
+			Self::Mattermost => "Mattermost",
+			Self::Matrix { .. } => "Matrix",
+		}
+	}
+
+	/// Check that the format can render killmails.
+	pub fn validate(&self) -> Result<()> {
+		match self {
+			Self::Template(template) => template.validate(),
+			_ => Ok(()),
+		}
+	}
 }
 
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
@@ -112,21 +361,39 @@ impl DerefMut for Subscriptions {
 	}
 }
 
-impl Value for Subscriptions {
-	type Error = Error;
+impl FromIterator<Subscription> for Subscriptions {
+	fn from_iter<T: IntoIterator<Item = Subscription>>(iter: T) -> Self {
+		Subscriptions(HashSet::from_iter(iter))
+	}
+}
 
-	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
-		Ok(bincode::deserialize(bytes)?)
+/// IDs of the subscriptions registered under a filter.
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct SubscriptionIds(HashSet<SubscriptionId>);
+
+impl Deref for SubscriptionIds {
+	type Target = HashSet<SubscriptionId>;
+
+	fn deref(&self) -> &Self::Target {
+		&self.0
 	}
+}
 
-	fn to_bytes(&self) -> Result<IVec, Self::Error> {
-		Ok(bincode::serialize(self)?.into())
+impl DerefMut for SubscriptionIds {
+	fn deref_mut(&mut self) -> &mut Self::Target {
+		&mut self.0
 	}
 }
 
-impl FromIterator<Subscription> for Subscriptions {
-	fn from_iter<T: IntoIterator<Item = Subscription>>(iter: T) -> Self {
-		Subscriptions(HashSet::from_iter(iter))
+impl Value for SubscriptionIds {
+	type Error = Error;
+
+	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
+		decode(bytes)
+	}
+
+	fn to_bytes(&self) -> Result<IVec, Self::Error> {
+		encode(self)
 	}
 }
 
@@ -1,8 +1,13 @@
-use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use serde_json::{from_value, Value};
 
 use super::{Filter, Involvement, Role};
+use crate::util::format_isk;
 
-// {"attackers":[{"alliance_id":99008829,"character_id":95990061,"corporation_id":98675241,"damage_done":2604,"final_blow":true,"security_status":-1.1,"ship_type_id":17709,"weapon_type_id":3512}],"killmail_id":96215665,"killmail_time":"2021-10-28T04:51:31Z","solar_system_id":30004979,"victim":{"alliance_id":99007969,"character_id":2119260464,"corporation_id":98536418,"damage_taken":2604,"items":[{"flag":11,"item_type_id":22291,"quantity_dropped":1,"singleton":0},{"flag":93,"item_type_id":31788,"quantity_destroyed":1,"singleton":0},{"flag":20,"item_type_id":380,"quantity_destroyed":1,"singleton":0},{"flag":27,"item_type_id":10631,"quantity_destroyed":1,"singleton":0},{"flag":30,"item_type_id":24473,"quantity_destroyed":33,"singleton":0},{"flag":19,"item_type_id":5973,"quantity_dropped":1,"singleton":0},{"flag":29,"item_type_id":24473,"quantity_destroyed":33,"singleton":0},{"flag":5,"item_type_id":24479,"quantity_dropped":2000,"singleton":0},{"flag":22,"item_type_id":448,"quantity_dropped":1,"singleton":0},{"flag":28,"item_type_id":10631,"quantity_destroyed":1,"singleton":0},{"flag":5,"item_type_id":24475,"quantity_destroyed":2160,"singleton":0},{"flag":30,"item_type_id":10631,"quantity_dropped":1,"singleton":0},{"flag":29,"item_type_id":10631,"quantity_destroyed":1,"singleton":0},{"flag":27,"item_type_id":24473,"quantity_dropped":33,"singleton":0},{"flag":28,"item_type_id":24473,"quantity_destroyed":33,"singleton":0},{"flag":12,"item_type_id":22291,"quantity_destroyed":1,"singleton":0},{"flag":92,"item_type_id":31788,"quantity_destroyed":1,"singleton":0},{"flag":5,"item_type_id":24473,"quantity_dropped":1800,"singleton":0},{"flag":94,"item_type_id":26929,"quantity_destroyed":1,"singleton":0},{"flag":21,"item_type_id":4027,"quantity_destroyed":1,"singleton":0}],"position":{"x":1398830485426.5562,"y":283874500452.13007,"z":919633873008.7272},"ship_type_id":602},"zkb":{"locationID":40315274,"hash":"cff36d79e4b17b6eca051a08b38a1b22170670dd","fittedValue":7777534.15,"droppedValue":4049768.47,"destroyedValue":4088340.88,"totalValue":8138109.35,"points":5,"npc":false,"solo":true,"awox":false,"esi":"https:\/\/esi.evetech.net\/latest\/killmails\/96215665\/cff36d79e4b17b6eca051a08b38a1b22170670dd\/","url":"https:\/\/zkillboard.com\/kill\/96215665\/"}}
+/// A killmail as received from the zKillboard websocket.
+pub const EXAMPLE: &str = r#"{"attackers":[{"alliance_id":99008829,"character_id":95990061,"corporation_id":98675241,"damage_done":2604,"final_blow":true,"security_status":-1.1,"ship_type_id":17709,"weapon_type_id":3512}],"killmail_id":96215665,"killmail_time":"2021-10-28T04:51:31Z","solar_system_id":30004979,"victim":{"alliance_id":99007969,"character_id":2119260464,"corporation_id":98536418,"damage_taken":2604,"items":[{"flag":11,"item_type_id":22291,"quantity_dropped":1,"singleton":0},{"flag":93,"item_type_id":31788,"quantity_destroyed":1,"singleton":0},{"flag":20,"item_type_id":380,"quantity_destroyed":1,"singleton":0},{"flag":27,"item_type_id":10631,"quantity_destroyed":1,"singleton":0},{"flag":30,"item_type_id":24473,"quantity_destroyed":33,"singleton":0},{"flag":19,"item_type_id":5973,"quantity_dropped":1,"singleton":0},{"flag":29,"item_type_id":24473,"quantity_destroyed":33,"singleton":0},{"flag":5,"item_type_id":24479,"quantity_dropped":2000,"singleton":0},{"flag":22,"item_type_id":448,"quantity_dropped":1,"singleton":0},{"flag":28,"item_type_id":10631,"quantity_destroyed":1,"singleton":0},{"flag":5,"item_type_id":24475,"quantity_destroyed":2160,"singleton":0},{"flag":30,"item_type_id":10631,"quantity_dropped":1,"singleton":0},{"flag":29,"item_type_id":10631,"quantity_destroyed":1,"singleton":0},{"flag":27,"item_type_id":24473,"quantity_dropped":33,"singleton":0},{"flag":28,"item_type_id":24473,"quantity_destroyed":33,"singleton":0},{"flag":12,"item_type_id":22291,"quantity_destroyed":1,"singleton":0},{"flag":92,"item_type_id":31788,"quantity_destroyed":1,"singleton":0},{"flag":5,"item_type_id":24473,"quantity_dropped":1800,"singleton":0},{"flag":94,"item_type_id":26929,"quantity_destroyed":1,"singleton":0},{"flag":21,"item_type_id":4027,"quantity_destroyed":1,"singleton":0}],"position":{"x":1398830485426.5562,"y":283874500452.13007,"z":919633873008.7272},"ship_type_id":602},"zkb":{"locationID":40315274,"hash":"cff36d79e4b17b6eca051a08b38a1b22170670dd","fittedValue":7777534.15,"droppedValue":4049768.47,"destroyedValue":4088340.88,"totalValue":8138109.35,"points":5,"npc":false,"solo":true,"awox":false,"esi":"https:\/\/esi.evetech.net\/latest\/killmails\/96215665\/cff36d79e4b17b6eca051a08b38a1b22170670dd\/","url":"https:\/\/zkillboard.com\/kill\/96215665\/"}}"#;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Killmail {
@@ -12,6 +17,13 @@ pub struct Killmail {
 	pub solar_system_id: usize,
 	pub victim: Victim,
 	pub zkb: Zkb,
+	/// Names of the IDs in the killmail, when they could be resolved.
+	#[serde(default, skip_serializing_if = "HashMap::is_empty")]
+	pub names: HashMap<usize, String>,
+	/// Share of recent kills of the same ship group worth less than this one, from 0 to 100, once
+	/// the group has enough of them.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub percentile: Option<u8>,
 }
 
 impl Killmail {
@@ -24,11 +36,79 @@ impl Killmail {
 				.flat_map(|attacker| attacker.filters()),
 		);
 		filters.extend(self.victim.filters());
+		if let Some(percentile) = self.percentile {
+			filters.extend((0..percentile).map(|percentile| Filter::Notable { percentile }));
+		}
 		filters
 	}
+
+	/// Every character, corporation, alliance, ship and system ID in the killmail.
+	pub fn ids(&self) -> HashSet<usize> {
+		let victim = &self.victim;
+		let mut ids = HashSet::new();
+		ids.insert(self.solar_system_id);
+		ids.insert(victim.corporation_id);
+		ids.insert(victim.ship_type_id);
+		ids.extend(victim.character_id);
+		ids.extend(victim.alliance_id);
+
+		for attacker in &self.attackers {
+			ids.extend(attacker.character_id);
+			ids.extend(attacker.corporation_id);
+			ids.extend(attacker.alliance_id);
+			ids.extend(attacker.ship_type_id);
+		}
+
+		ids
+	}
+
+	/// zKillboard's estimate of what was destroyed and dropped, unless it hasn't priced the kill.
+	pub fn value(&self) -> Option<f64> {
+		self.zkb.total_value
+	}
+
+	/// The resolved name of an ID, if there is one.
+	pub fn name(&self, id: usize) -> Option<&str> {
+		self.names.get(&id).map(String::as_str)
+	}
+
+	/// A one-line description of the kill followed by its zKillboard link, or just the link when
+	/// no names were resolved.
+	pub fn text(&self) -> String {
+		let victim = &self.victim;
+		let ship = self.name(victim.ship_type_id);
+		let system = self.name(self.solar_system_id);
+
+		let (ship, system) = match (ship, system) {
+			(Some(ship), Some(system)) => (ship, system),
+			_ => return self.zkb.url.clone(),
+		};
+
+		let pilot = victim
+			.character_id
+			.and_then(|id| self.name(id))
+			.or_else(|| self.name(victim.corporation_id));
+
+		let lost = match pilot {
+			Some(pilot) => format!("{} lost a **{}**", pilot, ship),
+			None => format!("**{}** destroyed", ship),
+		};
+
+		let worth = self
+			.value()
+			.map(|value| format!(" worth {} ISK", format_isk(value)))
+			.unwrap_or_default();
+
+		format!("{} in {}{}\n{}", lost, system, worth, self.zkb.url)
+	}
+
+	/// Whether any of the killmail's filters is in the set.
+	pub fn matches(&self, filters: &HashSet<Filter>) -> bool {
+		self.filters().iter().any(|filter| filters.contains(filter))
+	}
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Victim {
 	pub alliance_id: Option<usize>,
 	pub character_id: Option<usize>,
@@ -71,7 +151,7 @@ impl Victim {
 	}
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Attacker {
 	pub alliance_id: Option<usize>,
 	pub character_id: Option<usize>,
@@ -119,7 +199,133 @@ impl Attacker {
 	}
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Default)]
 pub struct Zkb {
+	/// Needed with the killmail ID to fetch the killmail from ESI.
+	#[serde(default, skip_serializing_if = "String::is_empty")]
+	pub hash: String,
+	#[serde(
+		rename = "totalValue",
+		default,
+		skip_serializing_if = "Option::is_none"
+	)]
+	pub total_value: Option<f64>,
+	#[serde(default)]
 	pub url: String,
 }
+
+/// A killmail reference carrying only enough to fetch the rest from ESI.
+#[derive(Debug, Deserialize)]
+pub struct PartialKillmail {
+	#[serde(alias = "killID")]
+	pub killmail_id: usize,
+	#[serde(default)]
+	pub hash: Option<String>,
+	#[serde(default)]
+	pub zkb: Zkb,
+}
+
+impl PartialKillmail {
+	pub fn hash(&self) -> Option<&str> {
+		self.hash
+			.as_deref()
+			.or_else(|| Some(self.zkb.hash.as_str()).filter(|hash| !hash.is_empty()))
+	}
+}
+
+/// A killmail as a source delivers it: complete, or partial and still to be fetched.
+#[derive(Debug)]
+pub enum Incoming {
+	Full(Box<Killmail>),
+	Partial(PartialKillmail),
+}
+
+impl<'de> Deserialize<'de> for Incoming {
+	/// Anything carrying the killmail's own fields is complete, so a malformed one fails instead of
+	/// passing as a partial killmail and being fetched again.
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		let value = Value::deserialize(deserializer)?;
+
+		if value.get("killmail_time").is_some() || value.get("victim").is_some() {
+			from_value(value).map(|km| Self::Full(Box::new(km)))
+		} else {
+			from_value(value).map(Self::Partial)
+		}
+		.map_err(D::Error::custom)
+	}
+}
+
+impl Incoming {
+	pub fn killmail_id(&self) -> usize {
+		match self {
+			Self::Full(km) => km.killmail_id,
+			Self::Partial(partial) => partial.killmail_id,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::{from_str, json, Value};
+
+	use super::{Incoming, Killmail, EXAMPLE};
+	use crate::model::Filter;
+
+	fn incoming(value: Value) -> serde_json::Result<Incoming> {
+		from_str(&value.to_string())
+	}
+
+	#[test]
+	fn deserializes_incoming() {
+		match from_str::<Incoming>(EXAMPLE).unwrap() {
+			Incoming::Full(km) => assert_eq!(km.value(), Some(8138109.35)),
+			Incoming::Partial(_) => panic!("full killmail read as partial"),
+		}
+
+		let partial = json!({ "killID": 96215665, "zkb": { "hash": "cff36d79" } });
+		match incoming(partial).unwrap() {
+			Incoming::Partial(partial) => {
+				assert_eq!(partial.hash(), Some("cff36d79"));
+				assert_eq!(partial.zkb.total_value, None);
+			}
+			Incoming::Full(_) => panic!("partial killmail read as full"),
+		}
+	}
+
+	#[test]
+	fn rejects_malformed_full_killmails() {
+		let mut km = from_str::<Value>(EXAMPLE).unwrap();
+		km["victim"]["ship_type_id"] = json!("Rifter");
+		assert!(incoming(km).is_err());
+
+		let mut km = from_str::<Value>(EXAMPLE).unwrap();
+		km.as_object_mut().unwrap().remove("victim");
+		assert!(incoming(km).is_err());
+	}
+
+	#[test]
+	fn matches_lower_notable_thresholds() {
+		let mut km = from_str::<Killmail>(EXAMPLE).unwrap();
+		let notable = |km: &Killmail| {
+			km.filters()
+				.into_iter()
+				.filter_map(|filter| match filter {
+					Filter::Notable { percentile } => Some(percentile),
+					_ => None,
+				})
+				.collect::<Vec<_>>()
+		};
+
+		assert!(notable(&km).is_empty());
+
+		// a kill ranked at 3 beats the thresholds below it
+		km.percentile = Some(3);
+		assert_eq!(notable(&km), vec![0, 1, 2]);
+
+		km.percentile = Some(0);
+		assert!(notable(&km).is_empty());
+
+		km.percentile = Some(100);
+		assert_eq!(notable(&km), (0..100).collect::<Vec<_>>());
+	}
+}
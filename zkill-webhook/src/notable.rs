@@ -0,0 +1,222 @@
+use std::{
+	collections::{HashMap, HashSet, VecDeque},
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use anyhow::{Error, Result};
+use serde::{Deserialize, Serialize};
+use sled::{Batch, IVec, Tree};
+use sled_ext::{key::Key, value::Value};
+use tokio::{select, spawn, time::interval};
+use tracing::log::warn;
+
+use crate::{
+	model::zkb::Killmail,
+	storage::{decode, encode},
+	util::stopped,
+	State,
+};
+/// A ship group, such as Frigate or Supercarrier.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BaselineKey(pub usize);
+
+/// Values of a ship group's latest kills, oldest first.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Baseline {
+	pub values: VecDeque<f64>,
+}
+
+impl Key for BaselineKey {
+	type Value = Baseline;
+
+	type Error = Error;
+
+	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
+		decode(bytes)
+	}
+
+	fn to_bytes(&self) -> Result<IVec, Self::Error> {
+		encode(self)
+	}
+}
+
+impl Value for Baseline {
+	type Error = Error;
+
+	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
+		decode(bytes)
+	}
+
+	fn to_bytes(&self) -> Result<IVec, Self::Error> {
+		encode(self)
+	}
+}
+
+impl Baseline {
+	/// Share of the values below `value`, from 0 to 100.
+	fn percentile(&self, value: f64) -> u8 {
+		let below = self.values.iter().filter(|other| **other < value).count();
+		(below * 100 / self.values.len()) as u8
+	}
+
+	fn push(&mut self, value: f64, limit: usize) {
+		self.values.push_back(value);
+		while self.values.len() > limit {
+			self.values.pop_front();
+		}
+	}
+}
+
+/// Recent kill values of every ship group, kept in memory since every kill changes one. Changes
+/// are written to sled periodically and at shutdown.
+#[derive(Debug)]
+pub struct Baselines {
+	tree: Tree,
+	baselines: Mutex<HashMap<usize, Baseline>>,
+	/// Groups changed since they were last written.
+	changed: Mutex<HashSet<usize>>,
+	/// Ship types being looked up in the background.
+	pending: Mutex<HashSet<usize>>,
+}
+
+impl Baselines {
+	/// Read every stored baseline into memory.
+	pub fn load(tree: Tree) -> Result<Self> {
+		let baselines = tree
+			.iter()
+			.map(|entry| {
+				let (key, value) = entry?;
+				Ok((
+					BaselineKey::from_bytes(&key)?.0,
+					Baseline::from_bytes(&value)?,
+				))
+			})
+			.collect::<Result<_>>()?;
+
+		Ok(Self {
+			tree,
+			baselines: Mutex::new(baselines),
+			changed: Mutex::default(),
+			pending: Mutex::default(),
+		})
+	}
+
+	/// Write the baselines changed since the last flush.
+	pub fn flush(&self) -> Result<()> {
+		let changed = std::mem::take(&mut *self.changed.lock().unwrap());
+		let baselines = self.baselines.lock().unwrap();
+
+		let mut batch = Batch::default();
+		for group in changed {
+			if let Some(baseline) = baselines.get(&group) {
+				batch.insert(BaselineKey(group).to_bytes()?, baseline.to_bytes()?);
+			}
+		}
+
+		Ok(self.tree.apply_batch(batch)?)
+	}
+}
+
+/// Look up the ship type in the background so later kills of it can be ranked.
+fn prefetch(state: &State, type_id: usize) {
+	if !state.baselines.pending.lock().unwrap().insert(type_id) {
+		return;
+	}
+
+	let esi = Arc::clone(&state.esi);
+	let baselines = Arc::clone(&state.baselines);
+	spawn(async move {
+		if let Err(e) = esi.item_type(type_id).await {
+			warn!("Unable to look up ship type {}: {}", type_id, e);
+		}
+		baselines.pending.lock().unwrap().remove(&type_id);
+	});
+}
+
+/// Rank the killmail's value against recent kills of the victim's ship group, then add it to
+/// them. There's no rank until the group has seen enough kills, or while its ship type is still
+/// being looked up.
+pub fn record(state: &State, km: &Killmail) -> Result<Option<u8>> {
+	let config = &state.config.notable;
+	// an unpriced kill would rank as the cheapest and drag the baseline down
+	let value = match km.value() {
+		Some(value) => value,
+		None => return Ok(None),
+	};
+
+	let type_id = km.victim.ship_type_id;
+	let group = match state.esi.cached_item_type(type_id)? {
+		Some(item_type) => item_type.group_id,
+		None => {
+			prefetch(state, type_id);
+			return Ok(None);
+		}
+	};
+
+	let mut baselines = state.baselines.baselines.lock().unwrap();
+	let baseline = baselines.entry(group).or_default();
+
+	let percentile = if baseline.values.len() >= config.min_samples {
+		Some(baseline.percentile(value))
+	} else {
+		None
+	};
+
+	baseline.push(value, config.samples);
+	state.baselines.changed.lock().unwrap().insert(group);
+
+	Ok(percentile)
+}
+
+/// Periodically write changed baselines until shutdown. The last changes are written once
+/// ingestion has stopped.
+pub async fn run(state: State) {
+	let mut interval = interval(Duration::from_secs(60));
+	let mut shutdown = state.shutdown.clone();
+
+	loop {
+		select! {
+			_ = interval.tick() => {}
+			_ = stopped(&mut shutdown) => break,
+		}
+
+		if let Err(e) = state.baselines.flush() {
+			warn!("Error saving notable baselines: {}", e);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Baseline;
+
+	fn baseline(values: &[f64]) -> Baseline {
+		Baseline {
+			values: values.iter().copied().collect(),
+		}
+	}
+
+	#[test]
+	fn ranks_against_lower_values() {
+		let values = baseline(&[10.0, 20.0, 30.0, 40.0]);
+
+		assert_eq!(values.percentile(5.0), 0);
+		// equal values don't count as below
+		assert_eq!(values.percentile(10.0), 0);
+		assert_eq!(values.percentile(25.0), 50);
+		assert_eq!(values.percentile(40.0), 75);
+		assert_eq!(values.percentile(50.0), 100);
+
+		// rounds down
+		assert_eq!(baseline(&[1.0, 2.0, 3.0]).percentile(2.5), 66);
+	}
+
+	#[test]
+	fn push_drops_oldest_values() {
+		let mut baseline = baseline(&[1.0, 2.0]);
+		baseline.push(3.0, 2);
+
+		assert_eq!(baseline.values, vec![2.0, 3.0]);
+	}
+}
@@ -0,0 +1,460 @@
+use std::{cell::Cell, collections::HashSet, sync::Arc};
+
+use axum::{
+	body::Bytes,
+	extract::{Extension, Path, Query},
+	http::{header::AUTHORIZATION, HeaderMap},
+	response::sse::{Event, KeepAlive, Sse},
+	Json,
+};
+use axum_msgpack::MsgPack;
+use futures::{stream, Stream};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, from_str};
+use tokio::{select, sync::broadcast::error::RecvError};
+use tracing::log::{error, warn};
+
+use crate::{
+	activity::{self, Profile},
+	backup::{self, Backup, Mode},
+	delivery_log::{self, DeliveryLog},
+	model::{Filter, Filters, Format, Perspective, Subscription, SubscriptionId},
+	stats::{self, Entity, EntityStats},
+	util::constant_time_eq,
+	State,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct Target {
+	pub webhook_url: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Registered {
+	pub id: SubscriptionId,
+	pub webhook_url: String,
+}
+
+/// A subscription's delivery log without its format, which can hold credentials.
+#[derive(Debug, Serialize)]
+pub struct Deliveries {
+	pub id: SubscriptionId,
+	pub webhook_url: String,
+	pub log: DeliveryLog,
+}
+
+/// The delivery log of a subscription removed for failing.
+#[derive(Debug, Serialize)]
+pub struct DeadLetter {
+	pub id: SubscriptionId,
+	pub subscription: Subscription,
+	pub log: DeliveryLog,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubscriptionPatch {
+	/// Replaces every filter the subscription is registered under.
+	pub filters: Option<HashSet<Filter>>,
+	/// Moving a Matrix subscription also needs `format`, with its access token.
+	pub webhook_url: Option<String>,
+	pub format: Option<Format>,
+	pub friendly: Option<Perspective>,
+	pub battles: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebhookParams {
+	pub webhook_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StreamParams {
+	/// JSON array of filters to match.
+	pub filters: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportParams {
+	#[serde(default)]
+	pub mode: Mode,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Expiry {
+	pub webhook_url: String,
+	/// Unix timestamp after which the subscriptions are removed, or `None` to never expire.
+	pub expires_at: Option<u64>,
+}
+
+pub async fn register_webhook(
+	state: Extension<State>,
+	MsgPack(body): MsgPack<Filters>,
+) -> Result<Json<Vec<Registered>>, StatusCode> {
+	if body.is_empty() {
+		return Err(StatusCode::BAD_REQUEST);
+	}
+
+	if let Some(e) = body.keys().find_map(|filter| filter.validate().err()) {
+		warn!("Rejecting invalid filter: {}", e);
+		return Err(StatusCode::BAD_REQUEST);
+	}
+
+	let invalid = body
+		.values()
+		.flat_map(|subs| subs.iter())
+		.find_map(|sub| sub.format.validate().err());
+
+	if let Some(e) = invalid {
+		warn!("Rejecting invalid format: {}", e);
+		return Err(StatusCode::BAD_REQUEST);
+	}
+
+	let mut registered = state
+		.index
+		.subscribe(&body)
+		.map_err(|e| {
+			error!("{}", e);
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?
+		.into_iter()
+		.map(|(id, sub)| Registered {
+			id,
+			webhook_url: sub.webhook_url,
+		})
+		.collect::<Vec<_>>();
+	registered.sort_by_key(|registered| registered.id);
+
+	Ok(Json(registered))
+}
+
+/// Change a subscription's webhook URL, format or friendly side, and replace the filters it's
+/// registered under. Subscription IDs are sequential, so this needs the admin token.
+pub async fn update_subscription(
+	state: Extension<State>,
+	Path(id): Path<u64>,
+	headers: HeaderMap,
+	MsgPack(body): MsgPack<SubscriptionPatch>,
+) -> StatusCode {
+	if let Err(status) = authorize(&state, &headers) {
+		return status;
+	}
+
+	if body.filters.as_ref().map_or(false, HashSet::is_empty) {
+		return StatusCode::BAD_REQUEST;
+	}
+
+	let invalid = body
+		.filters
+		.iter()
+		.flatten()
+		.find_map(|filter| filter.validate().err());
+
+	if let Some(e) = invalid {
+		warn!("Rejecting invalid filter: {}", e);
+		return StatusCode::BAD_REQUEST;
+	}
+
+	if let Some(Err(e)) = body.format.as_ref().map(Format::validate) {
+		warn!("Rejecting invalid format: {}", e);
+		return StatusCode::BAD_REQUEST;
+	}
+
+	let id = SubscriptionId(id);
+	let current = match state.index.subscription(id) {
+		Some(sub) => sub,
+		None => return StatusCode::NOT_FOUND,
+	};
+
+	// a new URL mustn't receive the stored Matrix access token unless it's given again
+	let moved = body
+		.webhook_url
+		.as_ref()
+		.map_or(false, |url| *url != current.webhook_url);
+	if moved && body.format.is_none() && matches!(current.format, Format::Matrix { .. }) {
+		warn!("Rejecting a new webhook URL without the Matrix access token");
+		return StatusCode::BAD_REQUEST;
+	}
+
+	let SubscriptionPatch {
+		filters,
+		webhook_url,
+		format,
+		friendly,
+		battles,
+	} = body;
+
+	let res = state.index.patch(
+		id,
+		|sub| {
+			if let Some(webhook_url) = webhook_url {
+				sub.webhook_url = webhook_url;
+			}
+
+			if let Some(format) = format {
+				sub.format = format;
+			}
+
+			if let Some(friendly) = friendly {
+				sub.friendly = friendly;
+			}
+
+			if let Some(battles) = battles {
+				sub.battles = battles;
+			}
+		},
+		filters,
+	);
+
+	match res {
+		Ok(true) => StatusCode::NO_CONTENT,
+		Ok(false) => StatusCode::NOT_FOUND,
+		Err(e) => {
+			error!("{}", e);
+			StatusCode::INTERNAL_SERVER_ERROR
+		}
+	}
+}
+
+/// Update every subscription posting to `webhook_url`.
+fn update_webhook<F>(state: &State, webhook_url: &str, f: F) -> StatusCode
+where
+	F: Fn(&mut Subscription),
+{
+	let found = Cell::new(false);
+	let res = state.index.update(|sub| {
+		let mut sub = sub.clone();
+		if sub.webhook_url == webhook_url {
+			found.set(true);
+			f(&mut sub);
+		}
+		Some(sub)
+	});
+
+	match res {
+		Ok(_) if !found.get() => StatusCode::NOT_FOUND,
+		Ok(_) => StatusCode::NO_CONTENT,
+		Err(e) => {
+			error!("{}", e);
+			StatusCode::INTERNAL_SERVER_ERROR
+		}
+	}
+}
+
+pub async fn pause(state: Extension<State>, MsgPack(body): MsgPack<Target>) -> StatusCode {
+	update_webhook(&state, &body.webhook_url, |sub| sub.paused = true)
+}
+
+pub async fn resume(state: Extension<State>, MsgPack(body): MsgPack<Target>) -> StatusCode {
+	update_webhook(&state, &body.webhook_url, |sub| sub.paused = false)
+}
+
+pub async fn set_expiry(state: Extension<State>, MsgPack(body): MsgPack<Expiry>) -> StatusCode {
+	update_webhook(&state, &body.webhook_url, |sub| {
+		sub.expires_at = body.expires_at
+	})
+}
+
+/// Check the request carries the configured admin token.
+fn authorize(state: &State, headers: &HeaderMap) -> Result<(), StatusCode> {
+	let token = state
+		.config
+		.admin
+		.token
+		.as_ref()
+		.ok_or(StatusCode::NOT_FOUND)?;
+
+	let expected = format!("Bearer {}", token);
+	match headers.get(AUTHORIZATION) {
+		Some(value) if constant_time_eq(value.as_bytes(), expected.as_bytes()) => Ok(()),
+		_ => Err(StatusCode::UNAUTHORIZED),
+	}
+}
+
+pub async fn export(
+	state: Extension<State>,
+	headers: HeaderMap,
+) -> Result<Json<Backup>, StatusCode> {
+	authorize(&state, &headers)?;
+	Ok(Json(backup::export(&state.index)))
+}
+
+pub async fn import(
+	state: Extension<State>,
+	headers: HeaderMap,
+	Query(params): Query<ImportParams>,
+	body: Bytes,
+) -> StatusCode {
+	if let Err(status) = authorize(&state, &headers) {
+		return status;
+	}
+
+	let backup = match from_slice::<Backup>(&body) {
+		Ok(backup) => backup,
+		Err(e) => {
+			warn!("Rejecting invalid import: {}", e);
+			return StatusCode::BAD_REQUEST;
+		}
+	};
+
+	if let Err(e) = backup.validate() {
+		warn!("Rejecting import: {}", e);
+		return StatusCode::BAD_REQUEST;
+	}
+
+	match backup::import(&state.index, backup, params.mode) {
+		Ok(()) => StatusCode::NO_CONTENT,
+		Err(e) => {
+			error!("{}", e);
+			StatusCode::INTERNAL_SERVER_ERROR
+		}
+	}
+}
+
+/// Delivery logs of the subscriptions posting to a webhook.
+pub async fn deliveries(
+	state: Extension<State>,
+	Query(params): Query<WebhookParams>,
+) -> Result<Json<Vec<Deliveries>>, StatusCode> {
+	let found = delivery_log::find(&state.delivery_log, |entry| {
+		entry.subscription.webhook_url == params.webhook_url
+	})
+	.map_err(|e| {
+		error!("{}", e);
+		StatusCode::INTERNAL_SERVER_ERROR
+	})?;
+
+	let deliveries = found
+		.into_iter()
+		.map(|(id, entry)| Deliveries {
+			id,
+			webhook_url: entry.subscription.webhook_url,
+			log: entry.log,
+		})
+		.collect();
+
+	Ok(Json(deliveries))
+}
+
+/// Kill and loss counts of a character, corporation, alliance, system or ship.
+pub async fn stats(
+	state: Extension<State>,
+	Path((kind, id)): Path<(String, usize)>,
+) -> Result<Json<EntityStats>, StatusCode> {
+	if !state.config.stats.enabled {
+		return Err(StatusCode::NOT_FOUND);
+	}
+
+	let entity = Entity::parse(&kind, id).ok_or(StatusCode::NOT_FOUND)?;
+
+	stats::get(&state.stats, entity).map(Json).map_err(|e| {
+		error!("{}", e);
+		StatusCode::INTERNAL_SERVER_ERROR
+	})
+}
+
+/// When a character, corporation or alliance is active, with an estimate of its timezone.
+pub async fn activity(
+	state: Extension<State>,
+	Path((kind, id)): Path<(String, usize)>,
+) -> Result<Json<Profile>, StatusCode> {
+	if !state.config.activity.enabled {
+		return Err(StatusCode::NOT_FOUND);
+	}
+
+	let entity = match Entity::parse(&kind, id) {
+		Some(entity @ Entity::Character(_))
+		| Some(entity @ Entity::Corporation(_))
+		| Some(entity @ Entity::Alliance(_)) => entity,
+		_ => return Err(StatusCode::NOT_FOUND),
+	};
+
+	activity::get(&state.activity, entity)
+		.map(Json)
+		.map_err(|e| {
+			error!("{}", e);
+			StatusCode::INTERNAL_SERVER_ERROR
+		})
+}
+
+/// Delivery logs of subscriptions removed for failing.
+pub async fn dead_letters(
+	state: Extension<State>,
+	headers: HeaderMap,
+) -> Result<Json<Vec<DeadLetter>>, StatusCode> {
+	authorize(&state, &headers)?;
+
+	let found = delivery_log::find(&state.delivery_log, |entry| entry.log.removed_at.is_some())
+		.map_err(|e| {
+			error!("{}", e);
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+
+	let dead_letters = found
+		.into_iter()
+		.map(|(id, entry)| DeadLetter {
+			id,
+			subscription: entry.subscription,
+			log: entry.log,
+		})
+		.collect();
+
+	Ok(Json(dead_letters))
+}
+
+/// Stream killmails matching the filters as server-sent events until the client disconnects or
+/// the server shuts down. A `lagged` event says how many killmails the client was too slow to
+/// receive.
+pub async fn stream(
+	state: Extension<State>,
+	Query(params): Query<StreamParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, serde_json::Error>>>, StatusCode> {
+	let filters = match from_str::<HashSet<Filter>>(&params.filters) {
+		Ok(filters) if !filters.is_empty() => Arc::new(filters),
+		Ok(_) => return Err(StatusCode::BAD_REQUEST),
+		Err(e) => {
+			warn!("Rejecting invalid stream filters: {}", e);
+			return Err(StatusCode::BAD_REQUEST);
+		}
+	};
+
+	if let Some(e) = filters.iter().find_map(|filter| filter.validate().err()) {
+		warn!("Rejecting invalid stream filter: {}", e);
+		return Err(StatusCode::BAD_REQUEST);
+	}
+
+	let killmails = state.killmails.subscribe();
+	let shutdown = state.shutdown.clone();
+	let tracked = state.index.track(Arc::clone(&filters));
+
+	let events = stream::unfold(
+		(killmails, shutdown, tracked),
+		move |(mut killmails, mut shutdown, tracked)| {
+			let filters = Arc::clone(&filters);
+
+			async move {
+				loop {
+					let received = select! {
+						received = killmails.recv() => received,
+						_ = shutdown.changed() => return None,
+					};
+
+					let event = match received {
+						Ok(km) if km.matches(&filters) => {
+							Event::default().event("killmail").json_data(&*km)
+						}
+						Ok(_) => continue,
+						Err(RecvError::Lagged(skipped)) => {
+							Ok(Event::default().event("lagged").data(skipped.to_string()))
+						}
+						Err(RecvError::Closed) => return None,
+					};
+
+					return Some((event, (killmails, shutdown, tracked)));
+				}
+			}
+		},
+	);
+
+	Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
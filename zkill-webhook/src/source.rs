@@ -0,0 +1,103 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::{future::BoxFuture, stream::BoxStream, StreamExt};
+use tokio::{select, sync::watch};
+use tracing::log::{error, info, warn};
+
+use crate::{
+	config::Config, delivery::process_killmail, index::Index, model::zkb::Incoming, util::stopped,
+	State,
+};
+
+pub mod replay;
+pub mod ws;
+
+/// Killmails as they arrive. Sources skip killmails they can't decode, so an error means the
+/// source itself failed and the stream should be given up.
+pub type KillmailStream = BoxStream<'static, Result<Incoming>>;
+
+/// Somewhere killmails come from.
+pub trait KillmailSource: Send + Sync {
+	/// Start streaming killmails. The stream ends when the source is exhausted or disconnects, or
+	/// once `shutdown` fires, after letting go of the source cleanly.
+	fn stream(&self, shutdown: watch::Receiver<bool>) -> BoxFuture<'_, Result<KillmailStream>>;
+
+	/// Whether to start streaming again once the stream ends.
+	fn restartable(&self) -> bool {
+		true
+	}
+}
+
+pub fn from_config(config: &Config, index: &Arc<Index>) -> Arc<dyn KillmailSource> {
+	let source = &config.source;
+
+	match &source.replay {
+		Some(path) => Arc::new(replay::Replay {
+			path: path.clone(),
+			pacing: source.pacing,
+		}),
+		None => Arc::new(ws::Websocket {
+			url: source.url.clone(),
+			subscribe: source.subscribe.clone(),
+			index: Some(Arc::clone(index)).filter(|_| source.max_channels > 0),
+			max_channels: source.max_channels,
+			killstream: config.killstream(),
+			battles: config.battles.enabled,
+		}),
+	}
+}
+
+async fn ingest(
+	state: &State,
+	source: &dyn KillmailSource,
+	shutdown: &mut watch::Receiver<bool>,
+) -> Result<()> {
+	let mut killmails = select! {
+		killmails = source.stream(shutdown.clone()) => killmails?,
+		_ = stopped(shutdown) => return Ok(()),
+	};
+
+	// the source ends the stream itself on shutdown
+	while let Some(km) = killmails.next().await {
+		let km = km?;
+
+		let km = match km {
+			Incoming::Full(km) => *km,
+			Incoming::Partial(partial) => {
+				let id = partial.killmail_id;
+				match state.esi.killmail(partial).await {
+					Ok(km) => km,
+					Err(e) => {
+						warn!("Unable to fetch killmail {} from ESI: {}", id, e);
+						continue;
+					}
+				}
+			}
+		};
+
+		if let Err(e) = process_killmail(state, km).await {
+			warn!("Error processing killmail: {}", e);
+		}
+	}
+
+	Ok(())
+}
+
+/// Feed killmails from the source to subscriptions until `shutdown` fires.
+pub async fn run(
+	state: State,
+	source: Arc<dyn KillmailSource>,
+	mut shutdown: watch::Receiver<bool>,
+) {
+	while !*shutdown.borrow() {
+		if let Err(e) = ingest(&state, &*source, &mut shutdown).await {
+			error!("{}", e);
+		}
+
+		if !source.restartable() {
+			info!("Killmail source is exhausted");
+			break;
+		}
+	}
+}
@@ -0,0 +1,83 @@
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::{Context, Error, Result};
+use futures::{future::BoxFuture, prelude::*, stream};
+use serde_json::from_str;
+use tokio::{
+	fs::File,
+	io::{AsyncBufReadExt, BufReader},
+	sync::watch,
+	time::sleep,
+};
+use tracing::log::warn;
+
+use super::{KillmailSource, KillmailStream};
+use crate::{
+	model::zkb::Incoming,
+	util::{parse_timestamp, stopped},
+};
+
+/// Killmails recorded as newline-delimited JSON, one killmail per line. Partial killmails are
+/// fetched from ESI as they're replayed.
+#[derive(Debug)]
+pub struct Replay {
+	pub path: PathBuf,
+	/// Wait between killmails for as long as passed between them when they were recorded.
+	pub pacing: bool,
+}
+
+impl KillmailSource for Replay {
+	fn stream(&self, mut shutdown: watch::Receiver<bool>) -> BoxFuture<'_, Result<KillmailStream>> {
+		async move {
+			let file = File::open(&self.path)
+				.await
+				.with_context(|| format!("Failed to open {}", self.path.display()))?;
+			let lines = BufReader::new(file).lines();
+			let pacing = self.pacing;
+
+			let initial = (lines, 0, None);
+			let killmails =
+				stream::unfold(initial, move |(mut lines, mut number, last)| async move {
+					let km = loop {
+						let line = match lines.next_line().await {
+							Ok(Some(line)) => line,
+							Ok(None) => return None,
+							Err(e) => return Some((Err(Error::from(e)), (lines, number, last))),
+						};
+						number += 1;
+
+						if line.trim().is_empty() {
+							continue;
+						}
+
+						// one bad line shouldn't cut the replay short
+						match from_str::<Incoming>(&line) {
+							Ok(km) => break km,
+							Err(e) => {
+								warn!("Skipping malformed killmail on line {}: {}", number, e)
+							}
+						}
+					};
+
+					let time = match &km {
+						Incoming::Full(km) => parse_timestamp(&km.killmail_time),
+						Incoming::Partial(_) => None,
+					};
+					if let (true, Some(last), Some(time)) = (pacing, last, time) {
+						sleep(Duration::from_secs(time.saturating_sub(last))).await;
+					}
+
+					Some((Ok(km), (lines, number, time.or(last))))
+				});
+
+			// stop mid-wait when pacing
+			let stop = async move { stopped(&mut shutdown).await };
+			Ok(killmails.take_until(stop).boxed())
+		}
+		.boxed()
+	}
+
+	fn restartable(&self) -> bool {
+		false
+	}
+}
@@ -0,0 +1,637 @@
+use std::{
+	collections::{HashSet, VecDeque},
+	sync::Arc,
+};
+
+use anyhow::{anyhow, Error, Result};
+use async_tungstenite::{
+	tokio::connect_async,
+	tungstenite::{Error as WsError, Message},
+};
+use futures::{future::BoxFuture, prelude::*, stream};
+use serde::Deserialize;
+use serde_json::{from_slice, from_str, from_value, json, Value};
+use tokio::{select, sync::watch};
+use tracing::log::{debug, info, warn};
+
+use super::{KillmailSource, KillmailStream};
+use crate::{
+	index::Index,
+	model::{zkb::Incoming, Filter},
+	util::stopped,
+};
+
+/// Killmail IDs remembered to drop a killmail arriving through several channels.
+const RECENT: usize = 1024;
+
+/// The zKillboard websocket.
+#[derive(Debug)]
+pub struct Websocket {
+	pub url: String,
+	/// Message sent after connecting to subscribe to every killmail.
+	pub subscribe: String,
+	/// Subscribe to the narrower channels the index's filters need instead, when set.
+	pub index: Option<Arc<Index>>,
+	/// Most channels to subscribe to before falling back to every killmail.
+	pub max_channels: usize,
+	/// Stay on the killstream whatever the filters need.
+	pub killstream: bool,
+	/// Whether battles are detected, so subscriptions to battle reports need the killstream.
+	pub battles: bool,
+}
+
+/// The zKillboard channel carrying every killmail matching the filter, if there's one narrower
+/// than the killstream. Channels don't distinguish attackers from victims.
+fn channel(filter: &Filter) -> Option<String> {
+	Some(match filter {
+		Filter::All | Filter::Notable { .. } => return None,
+		Filter::Character(involvement) => format!("character:{}", involvement.id),
+		Filter::Corporation(involvement) => format!("corporation:{}", involvement.id),
+		Filter::Alliance(involvement) => format!("alliance:{}", involvement.id),
+		Filter::System(id) => format!("system:{}", id),
+		Filter::Ship(involvement) => format!("ship:{}", involvement.id),
+	})
+}
+
+fn message(action: &str, channel: &str) -> Message {
+	Message::Text(json!({ "action": action, "channel": channel }).to_string())
+}
+
+/// Messages zKillboard sends besides killmails.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action")]
+enum Notice {
+	/// Tranquility's server status and player count.
+	#[serde(rename = "tqStatus")]
+	TqStatus {
+		#[serde(rename = "tqStatus")]
+		status: String,
+		#[serde(rename = "tqCount", default)]
+		count: Value,
+	},
+}
+
+/// A message from the websocket, classified without failing.
+#[derive(Debug)]
+enum Envelope {
+	Killmail(Box<Incoming>),
+	Notice(Notice),
+	/// Valid JSON that's neither a killmail nor a known notice.
+	Unknown(Value),
+	/// Not JSON, or a killmail that doesn't match the expected schema.
+	Invalid(Error),
+}
+
+/// Most characters of an unrecognized message that are logged.
+const SNIPPET_LEN: usize = 256;
+
+fn parse(data: &[u8]) -> Envelope {
+	let value = match from_slice::<Value>(data) {
+		Ok(value) => value,
+		Err(e) => return Envelope::Invalid(e.into()),
+	};
+
+	let id = value
+		.get("killmail_id")
+		.or_else(|| value.get("killID"))
+		.cloned();
+
+	if let Some(id) = id {
+		return match from_value(value) {
+			Ok(km) => Envelope::Killmail(Box::new(km)),
+			Err(e) => Envelope::Invalid(anyhow!("killmail {}: {}", id, e)),
+		};
+	}
+
+	match Notice::deserialize(&value) {
+		Ok(notice) => Envelope::Notice(notice),
+		Err(_) => Envelope::Unknown(value),
+	}
+}
+
+fn snippet(value: &Value) -> String {
+	value.to_string().chars().take(SNIPPET_LEN).collect()
+}
+
+/// What a session is currently subscribed to.
+#[derive(Debug, PartialEq)]
+enum Subscribed {
+	/// Every killmail, through the configured subscribe message.
+	Killstream,
+	Channels(HashSet<String>),
+}
+
+enum Event {
+	Message(Option<Result<Message, WsError>>),
+	Changed,
+	Shutdown,
+}
+
+struct Session<S> {
+	ws: S,
+	subscribe: String,
+	dynamic: Option<(Arc<Index>, watch::Receiver<()>)>,
+	max_channels: usize,
+	killstream: bool,
+	battles: bool,
+	subscribed: Option<Subscribed>,
+	recent: VecDeque<usize>,
+	shutdown: watch::Receiver<bool>,
+}
+
+impl<S> Session<S>
+where
+	S: Stream<Item = Result<Message, WsError>> + Sink<Message, Error = WsError> + Unpin + Send,
+{
+	fn wanted(&self) -> Subscribed {
+		let index = match &self.dynamic {
+			Some((index, _)) if !self.killstream => index,
+			_ => return Subscribed::Killstream,
+		};
+
+		if self.battles && index.needs_killstream() {
+			return Subscribed::Killstream;
+		}
+
+		match index
+			.interest()
+			.iter()
+			.map(channel)
+			.collect::<Option<HashSet<_>>>()
+		{
+			Some(channels) if channels.len() <= self.max_channels => Subscribed::Channels(channels),
+			_ => Subscribed::Killstream,
+		}
+	}
+
+	/// The configured subscribe message with its action swapped for `unsub`.
+	fn unsubscribe(&self) -> Result<Message> {
+		let mut msg = from_str::<Value>(&self.subscribe)?;
+		if let Some(msg) = msg.as_object_mut() {
+			msg.insert("action".into(), json!("unsub"));
+		}
+
+		Ok(Message::Text(msg.to_string()))
+	}
+
+	/// Subscribe to what the filters currently need and unsubscribe from what they don't.
+	async fn resubscribe(&mut self) -> Result<()> {
+		let wanted = self.wanted();
+		if self.subscribed.as_ref() == Some(&wanted) {
+			return Ok(());
+		}
+
+		let mut messages = vec![];
+		match (&self.subscribed, &wanted) {
+			(Some(Subscribed::Channels(old)), Subscribed::Channels(new)) => {
+				messages.extend(old.difference(new).map(|channel| message("unsub", channel)));
+				messages.extend(new.difference(old).map(|channel| message("sub", channel)));
+			}
+			(old, new) => {
+				match old {
+					Some(Subscribed::Killstream) => messages.push(self.unsubscribe()?),
+					Some(Subscribed::Channels(old)) => {
+						messages.extend(old.iter().map(|channel| message("unsub", channel)))
+					}
+					None => {}
+				}
+
+				match new {
+					Subscribed::Killstream => messages.push(Message::Text(self.subscribe.clone())),
+					Subscribed::Channels(new) => {
+						messages.extend(new.iter().map(|channel| message("sub", channel)))
+					}
+				}
+			}
+		}
+
+		for message in messages {
+			self.ws.send(message).await?;
+		}
+
+		match &wanted {
+			Subscribed::Killstream => info!("Subscribed to the killstream"),
+			Subscribed::Channels(channels) => info!("Subscribed to {} channels", channels.len()),
+		}
+
+		self.subscribed = Some(wanted);
+		Ok(())
+	}
+
+	/// Whether the killmail was already received through another channel.
+	fn seen(&mut self, killmail_id: usize) -> bool {
+		if self.recent.contains(&killmail_id) {
+			return true;
+		}
+
+		if self.recent.len() == RECENT {
+			self.recent.pop_front();
+		}
+		self.recent.push_back(killmail_id);
+
+		false
+	}
+
+	async fn next(&mut self) -> Option<Result<Incoming>> {
+		loop {
+			let event = {
+				let Self {
+					ws,
+					dynamic,
+					shutdown,
+					..
+				} = &mut *self;
+				let changed = async {
+					if let Some((_, changes)) = dynamic {
+						if changes.changed().await.is_ok() {
+							return;
+						}
+					}
+
+					future::pending::<()>().await
+				};
+
+				select! {
+					message = ws.next() => Event::Message(message),
+					_ = changed => Event::Changed,
+					_ = stopped(shutdown) => Event::Shutdown,
+				}
+			};
+
+			let message = match event {
+				Event::Message(message) => message?,
+				Event::Changed => match self.resubscribe().await {
+					Ok(()) => continue,
+					Err(e) => return Some(Err(e)),
+				},
+				Event::Shutdown => {
+					if let Err(e) = self.ws.close().await {
+						debug!("Error closing websocket: {}", e);
+					}
+					return None;
+				}
+			};
+
+			let envelope = match message {
+				Err(e) => return Some(Err(Error::from(e))),
+				Ok(Message::Binary(bytes)) => parse(&bytes),
+				Ok(Message::Text(data)) => parse(data.as_bytes()),
+				Ok(Message::Close(_frame)) => return None,
+				Ok(Message::Ping(data)) => match self.ws.send(Message::Pong(data)).await {
+					Ok(()) => continue,
+					Err(e) => return Some(Err(Error::from(e))),
+				},
+				Ok(Message::Pong(_data)) => continue,
+			};
+
+			match envelope {
+				Envelope::Killmail(km) if self.seen(km.killmail_id()) => continue,
+				Envelope::Killmail(km) => return Some(Ok(*km)),
+				Envelope::Notice(Notice::TqStatus { status, count }) => {
+					debug!("Tranquility is {} with {} players", status, count)
+				}
+				Envelope::Unknown(value) => {
+					warn!("Skipping unrecognized message: {}", snippet(&value))
+				}
+				Envelope::Invalid(e) => warn!("Skipping malformed message: {}", e),
+			}
+		}
+	}
+}
+
+impl KillmailSource for Websocket {
+	fn stream(&self, shutdown: watch::Receiver<bool>) -> BoxFuture<'_, Result<KillmailStream>> {
+		async move {
+			let (ws, _res) = connect_async(self.url.as_str()).await?;
+
+			let mut session = Session {
+				ws,
+				subscribe: self.subscribe.clone(),
+				// watch for changes before reading the filters so none are missed
+				dynamic: self
+					.index
+					.as_ref()
+					.map(|index| (Arc::clone(index), index.changes())),
+				max_channels: self.max_channels,
+				killstream: self.killstream,
+				battles: self.battles,
+				subscribed: None,
+				recent: VecDeque::with_capacity(RECENT),
+				shutdown,
+			};
+			session.resubscribe().await?;
+
+			let killmails = stream::unfold(session, |mut session| async move {
+				let km = session.next().await?;
+				Some((km, session))
+			});
+
+			Ok(killmails.boxed())
+		}
+		.boxed()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		collections::{HashSet, VecDeque},
+		pin::Pin,
+		sync::Arc,
+		task::{Context, Poll},
+	};
+
+	use async_tungstenite::tungstenite::{Error as WsError, Message};
+	use futures::{Sink, Stream};
+	use serde_json::{from_str, json, Value};
+	use tokio::sync::watch;
+
+	use super::{parse, Envelope, Notice, Session, Subscribed};
+	use crate::{
+		config::Config,
+		index::Index,
+		model::{
+			zkb::{Incoming, EXAMPLE},
+			Filter, Filters, Format, Involvement, Role, Subscription,
+		},
+	};
+
+	const SUBSCRIBE: &str = r#"{"action":"sub","channel":"killstream"}"#;
+
+	/// A websocket that never receives anything and records what's sent.
+	#[derive(Default)]
+	struct Recorder(Vec<Message>);
+
+	impl Stream for Recorder {
+		type Item = Result<Message, WsError>;
+
+		fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+			Poll::Pending
+		}
+	}
+
+	impl Sink<Message> for Recorder {
+		type Error = WsError;
+
+		fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+			Poll::Ready(Ok(()))
+		}
+
+		fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), WsError> {
+			self.get_mut().0.push(item);
+			Ok(())
+		}
+
+		fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+			Poll::Ready(Ok(()))
+		}
+
+		fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+			Poll::Ready(Ok(()))
+		}
+	}
+
+	fn index() -> Arc<Index> {
+		let db = sled::Config::new().temporary(true).open().unwrap();
+		Arc::new(Index::load(&db, "webhooks").unwrap())
+	}
+
+	fn session(index: Option<&Arc<Index>>, max_channels: usize) -> Session<Recorder> {
+		Session {
+			ws: Recorder::default(),
+			subscribe: SUBSCRIBE.into(),
+			dynamic: index.map(|index| (Arc::clone(index), index.changes())),
+			max_channels,
+			killstream: false,
+			battles: true,
+			subscribed: None,
+			recent: VecDeque::new(),
+			shutdown: watch::channel(false).1,
+		}
+	}
+
+	fn sub(battles: bool) -> Subscription {
+		Subscription {
+			webhook_url: "https://example.com/hook".into(),
+			format: Format::Raw,
+			digest: None,
+			paused: false,
+			expires_at: None,
+			friendly: Default::default(),
+			battles,
+		}
+	}
+
+	fn character(id: usize) -> Filter {
+		Filter::Character(Involvement {
+			id,
+			role: Role::Attacker,
+		})
+	}
+
+	fn subscribe(index: &Index, filter: Filter, sub: Subscription) {
+		let mut filters = Filters::new();
+		filters.insert(filter, Some(sub).into_iter().collect());
+		index.subscribe(&filters).unwrap();
+	}
+
+	fn channels(channels: &[&str]) -> Subscribed {
+		Subscribed::Channels(channels.iter().map(|channel| channel.to_string()).collect())
+	}
+
+	/// The messages sent since last taken, as JSON so their order within a kind doesn't matter.
+	fn sent(session: &mut Session<Recorder>) -> HashSet<String> {
+		session
+			.ws
+			.0
+			.drain(..)
+			.map(|message| match message {
+				Message::Text(text) => from_str::<Value>(&text).unwrap().to_string(),
+				other => panic!("unexpected message {:?}", other),
+			})
+			.collect()
+	}
+
+	fn messages(messages: &[Value]) -> HashSet<String> {
+		messages.iter().map(Value::to_string).collect()
+	}
+
+	#[test]
+	fn narrows_to_the_filters_channels() {
+		let index = index();
+		subscribe(&index, character(1), sub(false));
+		subscribe(&index, Filter::System(30_000_142), sub(false));
+
+		assert_eq!(
+			session(Some(&index), 10).wanted(),
+			channels(&["character:1", "system:30000142"])
+		);
+	}
+
+	#[test]
+	fn falls_back_to_the_killstream() {
+		// without an index there's nothing to narrow to
+		assert_eq!(session(None, 10).wanted(), Subscribed::Killstream);
+
+		let index = index();
+		subscribe(&index, character(1), sub(false));
+		subscribe(&index, character(2), sub(false));
+
+		// more channels than allowed
+		assert_eq!(session(Some(&index), 1).wanted(), Subscribed::Killstream);
+
+		// a filter with no channel of its own
+		let all = self::index();
+		subscribe(&all, Filter::All, sub(false));
+		assert_eq!(session(Some(&all), 10).wanted(), Subscribed::Killstream);
+
+		// battle reports need every kill in a system
+		let battles = self::index();
+		subscribe(&battles, character(1), sub(true));
+		assert_eq!(session(Some(&battles), 10).wanted(), Subscribed::Killstream);
+	}
+
+	#[test]
+	fn ignores_battles_nobody_gets() {
+		let index = index();
+		subscribe(&index, character(1), sub(true));
+
+		// battles aren't detected at all
+		let mut disabled = session(Some(&index), 10);
+		disabled.battles = false;
+		assert_eq!(disabled.wanted(), channels(&["character:1"]));
+
+		// the only subscription to them is paused
+		index
+			.update(|sub| {
+				Some(Subscription {
+					paused: true,
+					..sub.clone()
+				})
+			})
+			.unwrap();
+		assert_eq!(
+			session(Some(&index), 10).wanted(),
+			channels(&["character:1"])
+		);
+	}
+
+	#[test]
+	fn stays_on_the_killstream_while_counting() {
+		let index = index();
+		subscribe(&index, character(1), sub(false));
+
+		let wanted = |config: &Config| {
+			let mut session = session(Some(&index), 10);
+			session.killstream = config.killstream();
+			session.wanted()
+		};
+
+		let mut config = Config::default();
+		assert_eq!(wanted(&config), channels(&["character:1"]));
+
+		config.stats.enabled = true;
+		assert_eq!(wanted(&config), Subscribed::Killstream);
+
+		config.stats.enabled = false;
+		config.activity.enabled = true;
+		assert_eq!(wanted(&config), Subscribed::Killstream);
+	}
+
+	#[tokio::test]
+	async fn resubscribes_to_the_difference() {
+		let index = index();
+		let mut session = session(Some(&index), 10);
+		let unsub_all = json!({ "action": "unsub", "channel": "killstream" });
+		let message = |action: &str, channel: &str| json!({ "action": action, "channel": channel });
+
+		subscribe(&index, character(1), sub(false));
+		session.resubscribe().await.unwrap();
+		assert_eq!(
+			sent(&mut session),
+			messages(&[message("sub", "character:1")])
+		);
+
+		// nothing changed, so nothing is sent
+		session.resubscribe().await.unwrap();
+		assert!(sent(&mut session).is_empty());
+
+		subscribe(&index, character(2), sub(false));
+		session.resubscribe().await.unwrap();
+		assert_eq!(
+			sent(&mut session),
+			messages(&[message("sub", "character:2")])
+		);
+
+		let id = index.get(&character(1))[0].0;
+		index
+			.patch(id, |_| {}, Some(Some(character(3)).into_iter().collect()))
+			.unwrap();
+		session.resubscribe().await.unwrap();
+		assert_eq!(
+			sent(&mut session),
+			messages(&[
+				message("unsub", "character:1"),
+				message("unsub", "character:2"),
+				message("sub", "character:3"),
+			])
+		);
+
+		subscribe(&index, Filter::All, sub(false));
+		session.resubscribe().await.unwrap();
+		assert_eq!(
+			sent(&mut session),
+			messages(&[
+				message("unsub", "character:3"),
+				from_str(SUBSCRIBE).unwrap()
+			])
+		);
+
+		let id = index.get(&Filter::All)[0].0;
+		index.remove(id).unwrap();
+		subscribe(&index, character(4), sub(false));
+		session.resubscribe().await.unwrap();
+		assert_eq!(
+			sent(&mut session),
+			messages(&[unsub_all, message("sub", "character:4")])
+		);
+	}
+
+	#[test]
+	fn parses_every_envelope() {
+		match parse(EXAMPLE.as_bytes()) {
+			Envelope::Killmail(km) => assert!(matches!(*km, Incoming::Full(_))),
+			other => panic!("expected a killmail, got {:?}", other),
+		}
+
+		let partial = json!({ "killID": 96215665, "zkb": { "hash": "cff36d79" } });
+		match parse(partial.to_string().as_bytes()) {
+			Envelope::Killmail(km) => assert_eq!(km.killmail_id(), 96215665),
+			other => panic!("expected a killmail, got {:?}", other),
+		}
+
+		let status = json!({ "action": "tqStatus", "tqStatus": "ONLINE", "tqCount": "23,456" });
+		match parse(status.to_string().as_bytes()) {
+			Envelope::Notice(Notice::TqStatus { status, count }) => {
+				assert_eq!(status, "ONLINE");
+				assert_eq!(count, json!("23,456"));
+			}
+			other => panic!("expected a notice, got {:?}", other),
+		}
+
+		let unknown = json!({ "action": "littlekill", "channel": "public" });
+		match parse(unknown.to_string().as_bytes()) {
+			Envelope::Unknown(value) => assert_eq!(value, unknown),
+			other => panic!("expected an unknown message, got {:?}", other),
+		}
+
+		assert!(matches!(parse(b"not json"), Envelope::Invalid(_)));
+
+		let mut malformed = from_str::<Value>(EXAMPLE).unwrap();
+		malformed["victim"]["ship_type_id"] = json!("Rifter");
+		match parse(malformed.to_string().as_bytes()) {
+			Envelope::Invalid(e) => assert!(e.to_string().starts_with("killmail 96215665")),
+			other => panic!("expected an invalid killmail, got {:?}", other),
+		}
+	}
+}
@@ -0,0 +1,301 @@
+use std::{
+	collections::{HashMap, HashSet},
+	time::Duration,
+};
+
+use anyhow::{Error, Result};
+use serde::{Deserialize, Serialize};
+use sled::{Batch, IVec, Tree};
+use sled_ext::{key::Key, value::Value};
+use tokio::{select, time::interval};
+use tracing::log::{info, warn};
+
+use crate::{
+	model::zkb::Killmail,
+	storage::{decode, encode},
+	util::{now, parse_timestamp, stopped},
+	State,
+};
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// Days in each window, ending with the current UTC day.
+const DAILY: u64 = 1;
+const WEEKLY: u64 = 7;
+const MONTHLY: u64 = 30;
+
+/// Something kills and losses are counted for.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Entity {
+	Character(usize),
+	Corporation(usize),
+	Alliance(usize),
+	System(usize),
+	Ship(usize),
+}
+
+impl Entity {
+	/// The entity named by an API path segment, e.g. `corporation`.
+	pub fn parse(kind: &str, id: usize) -> Option<Self> {
+		Some(match kind {
+			"character" => Self::Character(id),
+			"corporation" => Self::Corporation(id),
+			"alliance" => Self::Alliance(id),
+			"system" => Self::System(id),
+			"ship" => Self::Ship(id),
+			_ => return None,
+		})
+	}
+}
+
+/// An entity's counts for one UTC day.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatsKey {
+	pub entity: Entity,
+	/// Days since the Unix epoch.
+	pub day: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq)]
+pub struct Counts {
+	pub kills: u64,
+	pub kill_value: f64,
+	pub losses: u64,
+	pub loss_value: f64,
+}
+
+/// An entity's counts over each window.
+#[derive(Debug, Serialize)]
+pub struct EntityStats {
+	pub daily: Counts,
+	pub weekly: Counts,
+	pub monthly: Counts,
+}
+
+impl Key for StatsKey {
+	type Value = Counts;
+
+	type Error = Error;
+
+	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
+		decode(bytes)
+	}
+
+	fn to_bytes(&self) -> Result<IVec, Self::Error> {
+		encode(self)
+	}
+}
+
+impl Value for Counts {
+	type Error = Error;
+
+	fn from_bytes(bytes: &IVec) -> Result<Self, Self::Error> {
+		decode(bytes)
+	}
+
+	fn to_bytes(&self) -> Result<IVec, Self::Error> {
+		encode(self)
+	}
+}
+
+impl Counts {
+	fn add(&mut self, other: &Self) {
+		self.kills += other.kills;
+		self.kill_value += other.kill_value;
+		self.losses += other.losses;
+		self.loss_value += other.loss_value;
+	}
+}
+
+/// Count the killmail as a loss for the victim's entities and, once each, a kill for the
+/// attackers' entities and the system it happened in.
+pub fn record(tree: &Tree, km: &Killmail) -> Result<()> {
+	let day = parse_timestamp(&km.killmail_time).unwrap_or_else(now) / DAY;
+	let value = km.value().unwrap_or_default();
+	let victim = &km.victim;
+
+	let mut losses = HashSet::new();
+	losses.insert(Entity::Corporation(victim.corporation_id));
+	losses.insert(Entity::Ship(victim.ship_type_id));
+	losses.extend(victim.character_id.map(Entity::Character));
+	losses.extend(victim.alliance_id.map(Entity::Alliance));
+
+	let mut kills = HashSet::new();
+	kills.insert(Entity::System(km.solar_system_id));
+	for attacker in &km.attackers {
+		kills.extend(attacker.character_id.map(Entity::Character));
+		kills.extend(attacker.corporation_id.map(Entity::Corporation));
+		kills.extend(attacker.alliance_id.map(Entity::Alliance));
+		kills.extend(attacker.ship_type_id.map(Entity::Ship));
+	}
+
+	let mut changes = HashMap::<_, Counts>::new();
+	for entity in kills {
+		let counts = changes.entry(entity).or_default();
+		counts.kills += 1;
+		counts.kill_value += value;
+	}
+	for entity in losses {
+		let counts = changes.entry(entity).or_default();
+		counts.losses += 1;
+		counts.loss_value += value;
+	}
+
+	let mut batch = Batch::default();
+	for (entity, change) in changes {
+		let key = StatsKey { entity, day };
+		let mut counts = key.get(tree)?.unwrap_or_default();
+		counts.add(&change);
+		batch.insert(key.to_bytes()?, counts.to_bytes()?);
+	}
+
+	Ok(tree.apply_batch(batch)?)
+}
+
+fn window(tree: &Tree, entity: Entity, today: u64, days: u64) -> Result<Counts> {
+	let mut total = Counts::default();
+
+	for day in today.saturating_sub(days - 1)..=today {
+		if let Some(counts) = (StatsKey { entity, day }).get(tree)? {
+			total.add(&counts);
+		}
+	}
+
+	Ok(total)
+}
+
+/// The entity's counts over the daily, weekly and monthly windows.
+pub fn get(tree: &Tree, entity: Entity) -> Result<EntityStats> {
+	windows(tree, entity, now() / DAY)
+}
+
+fn windows(tree: &Tree, entity: Entity, today: u64) -> Result<EntityStats> {
+	Ok(EntityStats {
+		daily: window(tree, entity, today, DAILY)?,
+		weekly: window(tree, entity, today, WEEKLY)?,
+		monthly: window(tree, entity, today, MONTHLY)?,
+	})
+}
+
+/// Remove days that have left every window.
+fn prune(tree: &Tree) -> Result<usize> {
+	let oldest = (now() / DAY).saturating_sub(MONTHLY - 1);
+
+	let mut batch = Batch::default();
+	let mut removed = 0;
+	for key in tree.iter().keys() {
+		let key = key?;
+		if StatsKey::from_bytes(&key)?.day < oldest {
+			batch.remove(key);
+			removed += 1;
+		}
+	}
+
+	tree.apply_batch(batch)?;
+	Ok(removed)
+}
+
+/// Periodically remove expired days until shutdown.
+pub async fn run(state: State) {
+	let mut interval = interval(Duration::from_secs(60 * 60));
+	let mut shutdown = state.shutdown.clone();
+
+	loop {
+		select! {
+			_ = interval.tick() => {}
+			_ = stopped(&mut shutdown) => break,
+		}
+
+		match prune(&state.stats) {
+			Ok(0) => {}
+			Ok(removed) => info!("Removed {} expired daily stats", removed),
+			Err(e) => warn!("Error removing expired stats: {}", e),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::from_str;
+	use sled_ext::key::Key;
+
+	use super::{record, windows, Counts, Entity, StatsKey, DAY};
+	use crate::{
+		model::zkb::{Killmail, EXAMPLE},
+		util::parse_timestamp,
+	};
+
+	fn counts(kills: u64, losses: u64) -> Counts {
+		Counts {
+			kills,
+			kill_value: kills as f64,
+			losses,
+			loss_value: losses as f64,
+		}
+	}
+
+	#[test]
+	fn rolls_up_windows() {
+		let db = sled::Config::new().temporary(true).open().unwrap();
+		let tree = db.open_tree("stats").unwrap();
+		let entity = Entity::Corporation(98_000_001);
+		let today = 19_000;
+
+		for (days_ago, kills, losses) in &[(0, 1, 0), (6, 2, 1), (7, 4, 0), (29, 0, 8), (30, 16, 0)]
+		{
+			StatsKey {
+				entity,
+				day: today - days_ago,
+			}
+			.insert(&tree, counts(*kills, *losses))
+			.unwrap();
+		}
+
+		// counts of other entities and of the future aren't included
+		StatsKey {
+			entity: Entity::Corporation(98_000_002),
+			day: today,
+		}
+		.insert(&tree, counts(32, 32))
+		.unwrap();
+		StatsKey {
+			entity,
+			day: today + 1,
+		}
+		.insert(&tree, counts(32, 32))
+		.unwrap();
+
+		let stats = windows(&tree, entity, today).unwrap();
+		assert_eq!(stats.daily, counts(1, 0));
+		assert_eq!(stats.weekly, counts(3, 1));
+		assert_eq!(stats.monthly, counts(7, 9));
+	}
+
+	#[test]
+	fn records_kills_and_losses() {
+		let db = sled::Config::new().temporary(true).open().unwrap();
+		let tree = db.open_tree("stats").unwrap();
+		let km = from_str::<Killmail>(EXAMPLE).unwrap();
+		let today = parse_timestamp(&km.killmail_time).unwrap() / DAY;
+		let value = km.value().unwrap();
+
+		record(&tree, &km).unwrap();
+
+		let stats = |entity| windows(&tree, entity, today).unwrap().daily;
+		let kill = Counts {
+			kills: 1,
+			kill_value: value,
+			..Counts::default()
+		};
+		let loss = Counts {
+			losses: 1,
+			loss_value: value,
+			..Counts::default()
+		};
+		assert_eq!(stats(Entity::Character(95_990_061)), kill);
+		assert_eq!(stats(Entity::Alliance(99_008_829)), kill);
+		assert_eq!(stats(Entity::System(30_004_979)), kill);
+		assert_eq!(stats(Entity::Character(2_119_260_464)), loss);
+		assert_eq!(stats(Entity::Ship(602)), loss);
+	}
+}
@@ -0,0 +1,354 @@
+use anyhow::{bail, ensure, Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use sled::{transaction::ConflictableTransactionError, Db, IVec, Transactional, Tree};
+use tracing::log::info;
+
+use crate::config::Config;
+
+/// Version of the encoding of keys and values in sled. Bump this and append a migration to
+/// [`MIGRATIONS`] whenever a stored type changes shape.
+pub const SCHEMA_VERSION: u8 = 1;
+
+/// Tree holding bookkeeping about the database itself.
+const META_TREE: &str = "meta";
+const VERSION_KEY: &str = "schema_version";
+
+/// Upgrades the database by one version, given the filter tree.
+type Migration = fn(&Db, &Tree) -> Result<()>;
+
+/// `MIGRATIONS[n]` upgrades the database from version `n` to `n + 1`.
+const MIGRATIONS: &[Migration] = &[v0::migrate];
+
+/// Serialize a value with the current schema version prepended.
+pub fn encode<T: Serialize>(value: &T) -> Result<IVec> {
+	let mut bytes = vec![SCHEMA_VERSION];
+	bincode::serialize_into(&mut bytes, value)?;
+	Ok(bytes.into())
+}
+
+/// Deserialize a value written by [`encode`], rejecting other schema versions.
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+	match bytes.split_first() {
+		Some((&SCHEMA_VERSION, rest)) => Ok(bincode::deserialize(rest)?),
+		Some((version, _)) => bail!("Unsupported storage version {}", version),
+		None => bail!("Unable to decode empty value"),
+	}
+}
+
+/// Bring the database up to [`SCHEMA_VERSION`], recording the applied version.
+pub fn migrate(db: &Db, config: &Config) -> Result<()> {
+	let meta = db.open_tree(META_TREE)?;
+	let tree = db.open_tree(&config.db.tree)?;
+
+	let mut version = match meta.get(VERSION_KEY)? {
+		Some(bytes) => *bytes.first().context("Empty schema version")?,
+		// nothing to migrate in a new database
+		None if tree.is_empty() => SCHEMA_VERSION,
+		// data written before versioning was introduced
+		None => 0,
+	};
+
+	ensure!(
+		version <= SCHEMA_VERSION,
+		"Database schema version {} is newer than the supported version {}",
+		version,
+		SCHEMA_VERSION
+	);
+
+	while version < SCHEMA_VERSION {
+		info!(
+			"Migrating storage from version {} to {}",
+			version,
+			version + 1
+		);
+		MIGRATIONS[version as usize](db, &tree)?;
+		version += 1;
+	}
+
+	meta.insert(VERSION_KEY, vec![version])?;
+	db.flush()?;
+
+	Ok(())
+}
+
+/// Replace the contents of a tree and record the new schema version in one transaction, so an
+/// interrupted migration is retried from the start.
+fn replace_all(db: &Db, tree: &Tree, entries: Vec<(IVec, IVec)>, version: u8) -> Result<()> {
+	let old = tree.iter().keys().collect::<Result<Vec<_>, _>>()?;
+	let meta = db.open_tree(META_TREE)?;
+
+	(tree, &meta)
+		.transaction(|(tree, meta)| {
+			for key in &old {
+				tree.remove(key.clone())?;
+			}
+
+			for (key, value) in &entries {
+				tree.insert(key.clone(), value.clone())?;
+			}
+
+			meta.insert(VERSION_KEY, vec![version])?;
+			Ok::<_, ConflictableTransactionError<sled::Error>>(())
+		})
+		.context("Failed to replace tree contents")
+}
+
+/// Data written before keys and values were versioned, when every filter held a copy of each of
+/// its subscriptions.
+mod v0 {
+	use std::collections::HashMap;
+
+	use anyhow::{bail, Result};
+	use bincode::deserialize;
+	use serde::Deserialize;
+	use sled::{Db, Tree};
+
+	use super::{encode, replace_all, SCHEMA_VERSION};
+	use crate::{
+		index::SUBSCRIPTIONS_TREE,
+		model::{self, Filter, StoredFilter, SubscriptionId, SubscriptionIds},
+	};
+
+	#[derive(Deserialize)]
+	struct Subscription {
+		webhook_url: String,
+		format: Format,
+	}
+
+	#[derive(Deserialize)]
+	enum Format {
+		Raw,
+		Discord,
+	}
+
+	#[derive(Deserialize)]
+	struct Involvement {
+		id: usize,
+		role: Role,
+	}
+
+	#[derive(Deserialize)]
+	enum Role {
+		Attacker,
+		Victim,
+	}
+
+	impl From<Involvement> for model::Involvement {
+		fn from(involvement: Involvement) -> Self {
+			Self {
+				id: involvement.id,
+				role: match involvement.role {
+					Role::Attacker => model::Role::Attacker,
+					Role::Victim => model::Role::Victim,
+				},
+			}
+		}
+	}
+
+	impl From<Subscription> for model::Subscription {
+		fn from(sub: Subscription) -> Self {
+			Self {
+				webhook_url: sub.webhook_url,
+				format: match sub.format {
+					Format::Raw => model::Format::Raw,
+					Format::Discord => model::Format::Discord,
+				},
+				digest: None,
+				paused: false,
+				expires_at: None,
+				friendly: Default::default(),
+				battles: false,
+			}
+		}
+	}
+
+	/// Keys were the internally tagged `Filter`: the variant name followed by its fields.
+	fn filter(bytes: &[u8]) -> Result<Filter> {
+		let tag = deserialize::<String>(bytes)?;
+		let involvement =
+			|| Ok::<_, bincode::Error>(deserialize::<(String, Involvement)>(bytes)?.1.into());
+
+		Ok(match tag.as_str() {
+			"All" => Filter::All,
+			"Character" => Filter::Character(involvement()?),
+			"Corporation" => Filter::Corporation(involvement()?),
+			"Alliance" => Filter::Alliance(involvement()?),
+			"Ship" => Filter::Ship(involvement()?),
+			_ => bail!("Unknown filter type {}", tag),
+		})
+	}
+
+	/// Move each distinct subscription into its own tree under a new ID and leave only the IDs in
+	/// the filter tree. The subscription tree is rebuilt from scratch, so an interrupted migration
+	/// can be retried.
+	pub fn migrate(db: &Db, tree: &Tree) -> Result<()> {
+		let subscriptions = db.open_tree(SUBSCRIPTIONS_TREE)?;
+		subscriptions.clear()?;
+
+		let mut ids = HashMap::<model::Subscription, SubscriptionId>::new();
+		let mut entries = vec![];
+
+		for entry in tree.iter() {
+			let (key, value) = entry?;
+			let mut filter_ids = SubscriptionIds::default();
+
+			for sub in deserialize::<Vec<Subscription>>(&value)? {
+				let sub = model::Subscription::from(sub);
+				let id = match ids.get(&sub) {
+					Some(id) => *id,
+					None => {
+						let id = SubscriptionId(db.generate_id()?);
+						subscriptions.insert(encode(&id)?, encode(&sub)?)?;
+						ids.insert(sub, id);
+						id
+					}
+				};
+
+				filter_ids.insert(id);
+			}
+
+			entries.push((
+				encode(&StoredFilter::from(&filter(&key)?))?,
+				encode(&filter_ids)?,
+			));
+		}
+
+		replace_all(db, tree, entries, SCHEMA_VERSION)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashSet;
+
+	use serde::Serialize;
+	use sled::{Db, IVec};
+
+	use super::{migrate, META_TREE, SCHEMA_VERSION, VERSION_KEY};
+	use crate::{
+		config::Config,
+		index::Index,
+		model::{Filter, Format, Involvement, Perspective, Role, Subscription},
+	};
+
+	/// Subscriptions as they were stored before versioning.
+	#[derive(Serialize)]
+	struct V0Subscription {
+		webhook_url: String,
+		format: V0Format,
+	}
+
+	#[derive(Serialize)]
+	enum V0Format {
+		Raw,
+		Discord,
+	}
+
+	#[derive(Serialize)]
+	struct V0Involvement {
+		id: usize,
+		role: V0Role,
+	}
+
+	#[derive(Serialize)]
+	enum V0Role {
+		#[allow(dead_code)]
+		Attacker,
+		Victim,
+	}
+
+	fn open() -> Db {
+		sled::Config::new().temporary(true).open().unwrap()
+	}
+
+	fn insert(db: &Db, tree: &str, key: IVec, value: IVec) {
+		db.open_tree(tree).unwrap().insert(key, value).unwrap();
+	}
+
+	/// Migrate the database, returning every subscription with its filters by webhook URL.
+	fn migrated(db: &Db) -> Vec<(Subscription, HashSet<Filter>)> {
+		let config = Config::default();
+		migrate(db, &config).unwrap();
+
+		let version = db.open_tree(META_TREE).unwrap().get(VERSION_KEY).unwrap();
+		assert_eq!(version.as_deref(), Some(&[SCHEMA_VERSION][..]));
+
+		let mut entries = Index::load(db, &config.db.tree)
+			.unwrap()
+			.entries()
+			.into_iter()
+			.map(|entry| (entry.subscription, entry.filters))
+			.collect::<Vec<_>>();
+		entries.sort_by(|a, b| a.0.webhook_url.cmp(&b.0.webhook_url));
+		entries
+	}
+
+	#[test]
+	fn migrates_v0() {
+		let db = open();
+		let tree = Config::default().db.tree;
+		let sub = |url: &str, format| V0Subscription {
+			webhook_url: url.into(),
+			format,
+		};
+
+		// keys were the internally tagged filter: its tag followed by its fields
+		let victim = V0Involvement {
+			id: 90_000_001,
+			role: V0Role::Victim,
+		};
+		insert(
+			&db,
+			&tree,
+			bincode::serialize(&("Character", victim)).unwrap().into(),
+			bincode::serialize(&vec![
+				sub("https://example.com/a", V0Format::Raw),
+				sub("https://example.com/b", V0Format::Discord),
+			])
+			.unwrap()
+			.into(),
+		);
+		insert(
+			&db,
+			&tree,
+			bincode::serialize(&("All",)).unwrap().into(),
+			bincode::serialize(&vec![sub("https://example.com/a", V0Format::Raw)])
+				.unwrap()
+				.into(),
+		);
+
+		let subscription = |url: &str, format| Subscription {
+			webhook_url: url.into(),
+			format,
+			digest: None,
+			paused: false,
+			expires_at: None,
+			friendly: Perspective::default(),
+			battles: false,
+		};
+		let character = Filter::Character(Involvement {
+			id: 90_000_001,
+			role: Role::Victim,
+		});
+
+		assert_eq!(
+			migrated(&db),
+			vec![
+				(
+					subscription("https://example.com/a", Format::Raw),
+					vec![character.clone(), Filter::All].into_iter().collect()
+				),
+				(
+					subscription("https://example.com/b", Format::Discord),
+					vec![character].into_iter().collect()
+				),
+			]
+		);
+	}
+
+	#[test]
+	fn starts_new_databases_at_the_current_version() {
+		let db = open();
+		assert!(migrated(&db).is_empty());
+	}
+}
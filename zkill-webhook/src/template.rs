@@ -0,0 +1,336 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, from_str, json, to_string, to_value, to_vec, Value};
+
+use crate::{
+	model::{
+		zkb::{Killmail, EXAMPLE},
+		Outcome,
+	},
+	util::format_isk,
+};
+
+/// A user-defined message, e.g. `{victim.ship} died in {system} worth {value}`.
+///
+/// Placeholders are dot-separated paths into the killmail, with array elements addressed by
+/// index. Braces that don't enclose a path are copied through as-is, so JSON templates need no
+/// escaping.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+pub struct Template {
+	pub source: String,
+	pub output: TemplateOutput,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum TemplateOutput {
+	/// Post the rendered text as the content of a Discord message.
+	Discord,
+	/// Post the rendered text as-is. The template must render a JSON document; string values are
+	/// escaped so they can be placed inside JSON string literals.
+	Raw,
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment<'a> {
+	Text(&'a str),
+	Field(&'a str),
+}
+
+fn is_path_char(c: char) -> bool {
+	c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+fn parse(source: &str) -> Vec<Segment<'_>> {
+	let mut segments = vec![];
+	let mut rest = source;
+
+	while let Some(start) = rest.find('{') {
+		let after = &rest[start + 1..];
+		let len = after
+			.find(|c: char| !is_path_char(c))
+			.unwrap_or_else(|| after.len());
+
+		if len > 0 && after[len..].starts_with('}') {
+			segments.push(Segment::Text(&rest[..start]));
+			segments.push(Segment::Field(&after[..len]));
+			rest = &after[len + 1..];
+		} else {
+			segments.push(Segment::Text(&rest[..=start]));
+			rest = after;
+		}
+	}
+
+	segments.push(Segment::Text(rest));
+	segments
+}
+
+/// The resolved name of the ID, falling back to the ID itself.
+fn named(km: &Killmail, id: usize) -> Value {
+	km.name(id).map_or_else(|| json!(id), |name| json!(name))
+}
+
+/// Build the values available to templates: the killmail itself plus some shorthands.
+fn context(km: &Killmail, outcome: Option<Outcome>) -> Result<Value> {
+	let mut ctx = to_value(km)?;
+	let final_blow = km
+		.attackers
+		.iter()
+		.find(|attacker| attacker.final_blow)
+		.map(to_value)
+		.transpose()?
+		.unwrap_or(Value::Null);
+
+	if let Value::Object(map) = &mut ctx {
+		map.insert("system".into(), named(km, km.solar_system_id));
+		map.insert("value".into(), json!(km.value().map(format_isk)));
+		map.insert("url".into(), json!(km.zkb.url));
+		map.insert("attacker_count".into(), json!(km.attackers.len()));
+		map.insert("final_blow".into(), final_blow);
+		map.insert("percentile".into(), json!(km.percentile));
+		map.insert(
+			"outcome".into(),
+			json!(outcome.map(|outcome| outcome.label())),
+		);
+	}
+
+	if let Some(Value::Object(victim)) = ctx.get_mut("victim") {
+		let named = |id: Option<usize>| id.map_or(Value::Null, |id| named(km, id));
+		victim.insert("ship".into(), named(Some(km.victim.ship_type_id)));
+		victim.insert("character".into(), named(km.victim.character_id));
+		victim.insert("corporation".into(), named(Some(km.victim.corporation_id)));
+		victim.insert("alliance".into(), named(km.victim.alliance_id));
+	}
+
+	Ok(ctx)
+}
+
+fn lookup<'a>(ctx: &'a Value, path: &str) -> Option<&'a Value> {
+	ctx.pointer(&format!("/{}", path.replace('.', "/")))
+}
+
+/// Whether the path leads somewhere in `ctx`. Elements of an array share a shape, so any index is
+/// checked against the first one.
+fn is_known(ctx: &Value, path: &str) -> bool {
+	let mut value = ctx;
+
+	for part in path.split('.') {
+		value = match value {
+			Value::Object(map) => match map.get(part) {
+				Some(value) => value,
+				None => return false,
+			},
+			Value::Array(items) if part.parse::<usize>().is_ok() => match items.first() {
+				Some(first) => first,
+				// nothing to check the rest of the path against
+				None => return true,
+			},
+			_ => return false,
+		};
+	}
+
+	true
+}
+
+impl Template {
+	/// Check that the template only references known fields and, for raw output, renders valid
+	/// JSON.
+	pub fn validate(&self) -> Result<()> {
+		let example = from_str::<Killmail>(EXAMPLE)?;
+		let ctx = context(&example, None)?;
+
+		for segment in parse(&self.source) {
+			match segment {
+				Segment::Field(path) if !is_known(&ctx, path) => {
+					bail!("Unknown template field {}", path)
+				}
+				_ => {}
+			}
+		}
+
+		if self.output == TemplateOutput::Raw {
+			// resolved names turn IDs into strings, which need quoting where the IDs didn't
+			let mut named = from_str::<Killmail>(EXAMPLE)?;
+			named.names = named
+				.ids()
+				.into_iter()
+				.map(|id| (id, format!("Name {}", id)))
+				.collect();
+
+			for km in &[example, named] {
+				from_slice::<Value>(&self.render(km, None)?)
+					.context("Template does not render valid JSON")?;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Render the template against a killmail and what it was from the subscription's side into a
+	/// request body.
+	pub fn render(&self, km: &Killmail, outcome: Option<Outcome>) -> Result<Vec<u8>> {
+		let ctx = context(km, outcome)?;
+
+		let mut text = String::with_capacity(self.source.len());
+		for segment in parse(&self.source) {
+			match segment {
+				Segment::Text(literal) => text.push_str(literal),
+				Segment::Field(path) => text.push_str(&self.display(lookup(&ctx, path))?),
+			}
+		}
+
+		match self.output {
+			TemplateOutput::Discord => Ok(to_vec(&json!({ "content": text }))?),
+			TemplateOutput::Raw => Ok(text.into_bytes()),
+		}
+	}
+
+	fn display(&self, value: Option<&Value>) -> Result<String> {
+		Ok(match (self.output, value) {
+			(TemplateOutput::Discord, None) | (TemplateOutput::Discord, Some(Value::Null)) => {
+				String::new()
+			}
+			(TemplateOutput::Discord, Some(Value::String(s))) => s.clone(),
+			(TemplateOutput::Raw, None) => "null".into(),
+			(TemplateOutput::Raw, Some(Value::String(s))) => {
+				let quoted = to_string(s)?;
+				quoted[1..quoted.len() - 1].to_string()
+			}
+			(_, Some(value)) => value.to_string(),
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{parse, Segment, Template, TemplateOutput};
+
+	fn template(source: &str, output: TemplateOutput) -> Template {
+		Template {
+			source: source.into(),
+			output,
+		}
+	}
+
+	#[test]
+	fn parses_fields() {
+		assert_eq!(
+			parse("{victim.ship} died in {system}"),
+			vec![
+				Segment::Text(""),
+				Segment::Field("victim.ship"),
+				Segment::Text(" died in "),
+				Segment::Field("system"),
+				Segment::Text(""),
+			]
+		);
+	}
+
+	#[test]
+	fn parses_nested_paths() {
+		assert_eq!(
+			parse("{attackers.0.character_id}"),
+			vec![
+				Segment::Text(""),
+				Segment::Field("attackers.0.character_id"),
+				Segment::Text(""),
+			]
+		);
+	}
+
+	#[test]
+	fn copies_other_braces() {
+		assert_eq!(
+			parse(r#"{"content": "{url}"}"#),
+			vec![
+				Segment::Text("{"),
+				Segment::Text(r#""content": ""#),
+				Segment::Field("url"),
+				Segment::Text(r#""}"#),
+			]
+		);
+
+		// doubled braces keep one pair around the value
+		assert_eq!(
+			parse("{{system}}"),
+			vec![
+				Segment::Text("{"),
+				Segment::Text(""),
+				Segment::Field("system"),
+				Segment::Text("}"),
+			]
+		);
+
+		assert_eq!(
+			parse("{} {not a field}"),
+			vec![
+				Segment::Text("{"),
+				Segment::Text("} {"),
+				Segment::Text("not a field}"),
+			]
+		);
+	}
+
+	#[test]
+	fn copies_unterminated_braces() {
+		assert_eq!(
+			parse("worth {value"),
+			vec![Segment::Text("worth {"), Segment::Text("value")]
+		);
+	}
+
+	#[test]
+	fn validates_full_paths() {
+		for source in &[
+			"{victim.ship}",
+			"{victim.alliance_id}",
+			"{attackers.3.ship_type_id}",
+			"{final_blow.character_id}",
+			"{zkb.totalValue}",
+			"{outcome} {percentile}",
+		] {
+			template(source, TemplateOutput::Discord)
+				.validate()
+				.unwrap();
+		}
+
+		for source in &[
+			"{victim.shipp}",
+			"{victim.ship.name}",
+			"{attackers.first}",
+			"{zkb..url}",
+			"{unknown}",
+		] {
+			assert!(template(source, TemplateOutput::Discord)
+				.validate()
+				.is_err());
+		}
+	}
+
+	#[test]
+	fn validates_raw_json() {
+		template(
+			r#"{"url": "{url}", "kills": {attacker_count}}"#,
+			TemplateOutput::Raw,
+		)
+		.validate()
+		.unwrap();
+
+		assert!(template(r#"{"url": {url}}"#, TemplateOutput::Raw)
+			.validate()
+			.is_err());
+	}
+
+	#[test]
+	fn validates_raw_json_with_names() {
+		template(r#"{"alliance": "{victim.alliance}"}"#, TemplateOutput::Raw)
+			.validate()
+			.unwrap();
+
+		// an ID until its name is resolved
+		assert!(
+			template(r#"{"alliance": {victim.alliance}}"#, TemplateOutput::Raw)
+				.validate()
+				.is_err()
+		);
+	}
+}
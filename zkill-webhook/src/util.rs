@@ -0,0 +1,75 @@
+use std::{
+	convert::TryFrom,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::watch;
+
+/// The current time as seconds since the Unix epoch.
+pub fn now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_secs())
+		.unwrap_or_default()
+}
+
+/// Resolve once `shutdown` fires, or once nothing is left to fire it.
+pub async fn stopped(shutdown: &mut watch::Receiver<bool>) {
+	while !*shutdown.borrow() {
+		if shutdown.changed().await.is_err() {
+			return;
+		}
+	}
+}
+
+/// Compare secrets without stopping at the first difference, so the time taken doesn't reveal how
+/// much of a guess was right.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Format an ISK value with a magnitude suffix, e.g. `1.23B`.
+pub fn format_isk(value: f64) -> String {
+	const SUFFIXES: [(f64, &str); 4] = [(1e12, "T"), (1e9, "B"), (1e6, "M"), (1e3, "K")];
+
+	SUFFIXES
+		.iter()
+		.find(|(scale, _)| value >= *scale)
+		.map(|(scale, suffix)| format!("{:.2}{}", value / scale, suffix))
+		.unwrap_or_else(|| format!("{:.2}", value))
+}
+
+/// Parse an ESI timestamp such as `2021-10-28T04:51:31Z` into seconds since the Unix epoch.
+pub fn parse_timestamp(timestamp: &str) -> Option<u64> {
+	let (date, time) = timestamp.strip_suffix('Z')?.split_once('T')?;
+
+	let mut date = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+	let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+
+	let mut time = time.splitn(3, ':').map(|part| part.parse::<i64>().ok());
+	let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+
+	// days since the epoch in the proleptic Gregorian calendar, from
+	// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+	let year = if month <= 2 { year - 1 } else { year };
+	let era = (if year >= 0 { year } else { year - 399 }) / 400;
+	let year_of_era = year - era * 400;
+	let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+	let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+	let days = era * 146_097 + day_of_era - 719_468;
+
+	u64::try_from(days * 86_400 + hour * 3_600 + minute * 60 + second).ok()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::constant_time_eq;
+
+	#[test]
+	fn compares_secrets() {
+		assert!(constant_time_eq(b"Bearer secret", b"Bearer secret"));
+		assert!(!constant_time_eq(b"Bearer secret", b"Bearer secreT"));
+		assert!(!constant_time_eq(b"Bearer secret", b"Bearer secret2"));
+		assert!(!constant_time_eq(b"", b"Bearer secret"));
+	}
+}